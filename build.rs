@@ -0,0 +1,40 @@
+use std::process::Command;
+
+// With the `index` feature visiogen enables `#![feature(generic_const_exprs)]`
+// (needed by CBL), so a stable compiler fails with an obscure feature-gate
+// error. Check up front and record the compiler and features for `--version`.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=RUSTC_BOOTSTRAP");
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|v| v.trim().to_string())
+        .unwrap_or_else(|| "unknown rustc".to_string());
+
+    let index = std::env::var_os("CARGO_FEATURE_INDEX").is_some();
+    let nightly = version.contains("nightly") || version.contains("-dev");
+    if index && !nightly && std::env::var_os("RUSTC_BOOTSTRAP").is_none() {
+        panic!(
+            "visiogen requires a nightly Rust toolchain because it enables \
+             #![feature(generic_const_exprs)] for the CBL index; found `{}`. \
+             Run `rustup toolchain install nightly-2024-05-31` (pinned in rust-toolchain.toml), \
+             or build with `--no-default-features` for a stable build without indexing.",
+            version
+        );
+    }
+
+    println!("cargo:rustc-env=VISIOGEN_RUSTC_VERSION={}", version);
+    println!(
+        "cargo:rustc-env=VISIOGEN_FEATURES={}",
+        if index {
+            "index (nightly: generic_const_exprs, used by the CBL index)"
+        } else {
+            "none (stable build; build command and off-target screening disabled)"
+        }
+    );
+}
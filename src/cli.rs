@@ -1,9 +1,19 @@
-use clap::{ArgAction, Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+
+use crate::core::probes::ScoreWeights;
 
 #[derive(Parser)]
 #[command(
     author = "Sam Dougan",
     version = "0.0.1",
+    long_version = concat!(
+        "0.0.1\n",
+        "built with: ",
+        env!("VISIOGEN_RUSTC_VERSION"),
+        "\n",
+        "features: ",
+        env!("VISIOGEN_FEATURES")
+    ),
     about = "A kmer-based probe design tool (primary usage: provide fasta, gff, and genes directly)",
     long_about = "This tool is primarily used by providing input files and gene lists directly. \
     The main functionality works with -f/--fasta, -a/--annotation, and -g/--genes arguments. \
@@ -31,20 +41,18 @@ pub struct Args {
     pub threads: usize,
 
     #[arg(
-        short = 'i',
-        long = "off_target_directory",
+        long = "build_threads",
         global = true,
-        help = "Directory containing off-target FASTA/index files"
+        help = "Threads for building indexes, overriding --threads (0 = all available cores)"
     )]
-    pub off_target_directory: Option<String>,
+    pub build_threads: Option<usize>,
 
     #[arg(
-        long = "max_hits",
-        default_value_t = 5,
+        long = "query_threads",
         global = true,
-        help = "Maximum number of index hits to report per kmer"
+        help = "Threads for querying off-target indexes, overriding --threads (0 = all available cores)"
     )]
-    pub max_hits: usize,
+    pub query_threads: Option<usize>,
 
     #[arg(
         short = 'r',
@@ -55,38 +63,554 @@ pub struct Args {
     )]
     pub recursive: bool,
 
-    #[arg(short = 'g', long = "gfa", help = "graph to generate probes from")]
-    pub gfa_path: String,
-
     #[arg(
         short = 'n',
         long = "probe_count",
-        help = "graph to generate probes from",
+        help = "Number of best-scoring probes to keep per target (0 = all)",
         default_value_t = 10
     )]
     pub n_count: u16,
 
+    #[arg(
+        long = "score_weights",
+        default_value = "gc=0.5,complexity=0.5",
+        help = "Weights of the probe score factors gc, complexity, gc3 (3' G/C clamp), copies (repeat penalty) and junction (runs around the junction base), normalised to sum to 1"
+    )]
+    pub score_weights: ScoreWeights,
+
+    #[arg(
+        long = "tm_target",
+        global = true,
+        help = "Select the probes whose Tm (C) is closest to this instead of the best-scoring ones, to even out Tm across the panel"
+    )]
+    pub tm_target: Option<f64>,
+
+    #[arg(
+        long = "gene_options",
+        global = true,
+        help = "TSV of per-gene k-mer option overrides: a gene name, then key=value fields named like the flags (e.g. min_gc=30)"
+    )]
+    pub gene_options: Option<String>,
+
+    #[arg(
+        long = "avoid_vcf",
+        global = true,
+        help = "VCF (or .bed) of variant sites; probes overlapping any site are dropped"
+    )]
+    pub avoid_vcf: Option<String>,
+
+    #[arg(
+        long = "contig_edge_buffer",
+        default_value_t = 0,
+        global = true,
+        help = "Drop probes within this many bp of either end of their contig in the reference (0 = off)"
+    )]
+    pub contig_edge_buffer: usize,
+
+    #[arg(
+        long = "pan_targets",
+        value_delimiter = ',',
+        global = true,
+        help = "Comma-separated FASTAs of further strains a probe must also be found in (pan-probe design); each probe reports the fraction of targets holding it"
+    )]
+    pub pan_targets: Vec<String>,
+
+    #[arg(
+        long = "min_pan_fraction",
+        default_value_t = 1.0,
+        global = true,
+        help = "With --pan_targets, keep probes found in at least this fraction of the targets, their own included (0-1, 1 = all)"
+    )]
+    pub min_pan_fraction: f64,
+
+    #[arg(
+        long = "tiling",
+        value_enum,
+        default_value_t = Tiling::Best,
+        help = "Probe selection: best-scoring probes, or contiguous end-to-end windows"
+    )]
+    pub tiling: Tiling,
+
+    #[arg(
+        long = "keep_n_per_window",
+        default_value_t = 0,
+        global = true,
+        help = "Keep the top N probes in each kmer-sized window of a target for redundant coverage, within --probe_count (0 = off)"
+    )]
+    pub keep_n_per_window: usize,
+
+    #[arg(
+        long = "min_probes_warn",
+        default_value_t = 0,
+        global = true,
+        help = "Warn about every target left with fewer than this many probes (0 = off)"
+    )]
+    pub min_probes_warn: usize,
+
+    #[arg(
+        long = "drop_shared",
+        action = ArgAction::SetTrue,
+        global = true,
+        help = "Drop probes shared by more than one target (e.g. paralogs) instead of only reporting them"
+    )]
+    pub drop_shared: bool,
+
+    #[arg(
+        long = "global_ranking",
+        action = ArgAction::SetTrue,
+        global = true,
+        help = "Write all probes as one list sorted by score across genes instead of grouped by gene"
+    )]
+    pub global_ranking: bool,
+
+    #[arg(
+        long = "collapse_duplicates",
+        action = ArgAction::SetTrue,
+        global = true,
+        help = "Write each distinct probe sequence once, listing every gene and position it serves in the header (FASTA only)"
+    )]
+    pub collapse_duplicates: bool,
+
+    #[arg(
+        long = "max_runtime",
+        global = true,
+        help = "Soft time limit in seconds: stop starting new targets once reached and write the probes designed so far"
+    )]
+    pub max_runtime: Option<u64>,
+
+    #[arg(
+        long = "flank",
+        default_value_t = 0,
+        global = true,
+        help = "Also output each selected probe with this many bases of genomic context on each side (0 = off)"
+    )]
+    pub flank: usize,
+
+    #[arg(
+        long = "report_coverage_gaps",
+        action = ArgAction::SetTrue,
+        global = true,
+        help = "Write a BED of the regions of each target that no written probe covers"
+    )]
+    pub report_coverage_gaps: bool,
+
+    #[arg(
+        long = "final_panel",
+        global = true,
+        help = "Also write just the probes passing every filter with no counted off-target hits to this FASTA"
+    )]
+    pub final_panel: Option<String>,
+
+    #[arg(
+        long = "report_format",
+        value_enum,
+        default_value_t = ReportFormat::Human,
+        global = true,
+        help = "Format of the end-of-run summary: human (logged), tsv or json (to stdout unless --report_file)"
+    )]
+    pub report_format: ReportFormat,
+
+    #[arg(
+        long = "report_file",
+        global = true,
+        help = "Write the end-of-run summary to this file (a human summary is still logged too)"
+    )]
+    pub report_file: Option<String>,
+
+    #[arg(
+        long = "summary_only",
+        action = ArgAction::SetTrue,
+        global = true,
+        help = "Run the whole pipeline but write no probe files, only the run summary (printed to stdout)"
+    )]
+    pub summary_only: bool,
+
+    #[arg(
+        long = "log_format",
+        value_enum,
+        default_value_t = LogFormat::Text,
+        global = true,
+        help = "Format of the log file: text (message, then key=value fields) or json (one object per line)"
+    )]
+    pub log_format: LogFormat,
+
+    #[arg(
+        long = "fasta_case",
+        value_enum,
+        default_value_t = FastaCase::Upper,
+        global = true,
+        help = "Case to put target and off-target sequences in before tiling or indexing; preserve keeps soft-masking for --skip_masked"
+    )]
+    pub fasta_case: FastaCase,
+
+    #[arg(
+        long = "dump_kmers",
+        global = true,
+        help = "Debug: write every candidate k-mer with its metrics, before any filtering, to this TSV"
+    )]
+    pub dump_kmers: Option<String>,
+
+    #[arg(
+        long = "tmpdir",
+        global = true,
+        help = "Directory for intermediate files such as in-progress index writes (e.g. node-local scratch)"
+    )]
+    pub tmpdir: Option<String>,
+
+    #[arg(
+        long = "io_retries",
+        default_value_t = 3,
+        global = true,
+        help = "Retry transient failures opening or creating index files this many times, with backoff (0 = no retries)"
+    )]
+    pub io_retries: u32,
+
+    #[arg(
+        long = "strict",
+        default_value_t = false,
+        global = true,
+        help = "Turn input consistency warnings into errors"
+    )]
+    pub strict: bool,
+
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = OutputFormat::Fasta,
+        global = true,
+        help = "Output format for selected probes"
+    )]
+    pub format: OutputFormat,
+
+    #[arg(
+        long = "output_revcomp",
+        default_value_t = false,
+        global = true,
+        help = "Write the reverse complement (antisense) of each probe in FASTA output"
+    )]
+    pub output_revcomp: bool,
+
+    #[arg(
+        long = "wrap",
+        default_value_t = 0,
+        global = true,
+        help = "Wrap FASTA sequence lines at this many characters (0 = no wrapping)"
+    )]
+    pub wrap: usize,
+
     #[command(flatten)]
     pub kmer_options: KmerOptions,
 
+    #[command(flatten)]
+    pub off_target: OffTargetOptions,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tiling {
+    /// Keep the highest scoring probes per gene (see --probe_count)
+    Best,
+    /// Windows at offsets 0, k, 2k, ... covering each target with no gaps or overlaps
+    Contiguous,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One record per probe
+    Fasta,
+    /// Probes as `probe` features under their gene, for genome browsers
+    Gff3,
+    /// `gene,sequence,specificity` rows with a header, for spreadsheets
+    Csv,
+    /// One JSON object per probe per line, for streaming into other tools
+    Jsonl,
+}
+
+/// Bits of each k-mer prefix the CBL index buckets on. Each step up
+/// multiplies the fixed bucket table by 16: more memory, even for small
+/// indexes, in exchange for shorter buckets and faster inserts and lookups on
+/// large ones.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefixBits {
+    /// Smallest table, for small genomes or memory-constrained machines
+    #[value(name = "20")]
+    P20,
+    /// Balanced default
+    #[value(name = "24")]
+    P24,
+    /// Largest table, for the fastest queries on large indexes
+    #[value(name = "28")]
+    P28,
+}
+
+#[cfg(feature = "index")]
+impl PrefixBits {
+    pub fn bits(&self) -> usize {
+        match self {
+            PrefixBits::P20 => 20,
+            PrefixBits::P24 => 24,
+            PrefixBits::P28 => 28,
+        }
+    }
+
+    pub fn from_bits(bits: usize) -> Option<Self> {
+        match bits {
+            20 => Some(PrefixBits::P20),
+            24 => Some(PrefixBits::P24),
+            28 => Some(PrefixBits::P28),
+            _ => None,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Aligned text in the log
+    Human,
+    /// A header row and a value row
+    Tsv,
+    /// One JSON object
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FastaCase {
+    /// Uppercase every base, so soft-masked bases are treated like any other
+    Upper,
+    /// Lowercase every base
+    Lower,
+    /// Keep the case of the input, e.g. soft-masking
+    Preserve,
+}
+
+impl FastaCase {
+    /// Rewrite `seq` in this case (unchanged for `Preserve`).
+    pub fn apply(&self, seq: &mut str) {
+        match self {
+            FastaCase::Upper => seq.make_ascii_uppercase(),
+            FastaCase::Lower => seq.make_ascii_lowercase(),
+            FastaCase::Preserve => {}
+        }
+    }
+
+    /// As `apply`, for raw sequence bytes.
+    pub fn apply_bytes(&self, seq: &mut [u8]) {
+        match self {
+            FastaCase::Upper => seq.make_ascii_uppercase(),
+            FastaCase::Lower => seq.make_ascii_lowercase(),
+            FastaCase::Preserve => {}
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `time [LEVEL] message | key=value ...`
+    Text,
+    /// One JSON object per record, fields as keys
+    Json,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Fasta => "fasta",
+            OutputFormat::Gff3 => "gff3",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     Gff(GffArgs),
 
+    Graph(GraphArgs),
+
+    #[command(name = "probes-from-fasta")]
+    ProbesFromFasta(ProbesFromFastaArgs),
+
     Build(BuildArgs),
+
+    Stats(StatsArgs),
+
+    /// Recompute metrics for an existing probe FASTA and write them as TSV and BED
+    Annotate(AnnotateArgs),
+
+    /// Check the build features, log directory, index round trip and thread count
+    Doctor,
 }
 
 #[derive(Parser, Debug, Clone)]
 pub struct GffArgs {
-    #[arg(short = 'a', long = "annotation")]
-    pub in_gff: String,
+    #[arg(
+        short = 'f',
+        long = "fasta",
+        help = "Reference FASTA the annotation refers to (optional when the GFF embeds a ##FASTA section)"
+    )]
+    pub in_fasta: Option<String>,
+
+    #[arg(
+        short = 'a',
+        long = "annotation",
+        required_unless_present = "bed",
+        help = "GFF3 annotation the gene names are looked up in"
+    )]
+    pub in_gff: Option<String>,
 
-    #[arg(short = 'g', long = "genes", help = "Gene identifier")]
-    pub genes: String,
+    #[arg(
+        long = "bed",
+        conflicts_with = "in_gff",
+        help = "BED of target regions (chrom, start, end[, name, score, strand]) to use instead of a GFF; '-' reads stdin"
+    )]
+    pub bed: Option<String>,
+
+    #[arg(
+        short = 'g',
+        long = "genes",
+        required_unless_present = "between",
+        conflicts_with = "between",
+        help = "Comma-separated gene names, or 'all' for every feature of --feature_type (or every BED region)"
+    )]
+    pub genes: Option<String>,
+
+    #[arg(
+        long = "between",
+        value_name = "GENE_A,GENE_B",
+        conflicts_with = "exon_junctions",
+        help = "Design probes for the region between two genes on the same contig and strand, instead of -g"
+    )]
+    pub between: Option<String>,
+
+    #[arg(
+        long = "feature_type",
+        default_value = "gene",
+        help = "GFF feature type to match gene names against"
+    )]
+    pub feature_type: String,
+
+    #[arg(
+        long = "exclude_genes",
+        help = "Genes to leave out of the target set: a comma-separated list, or a file with one name per line"
+    )]
+    pub exclude_genes: Option<String>,
+
+    #[arg(
+        long = "exon_junctions",
+        action = ArgAction::SetTrue,
+        help = "Tile each gene's spliced transcript (exon features) and keep only probes spanning an exon-exon junction"
+    )]
+    pub exon_junctions: bool,
+}
+
+impl GffArgs {
+    pub fn all_genes(&self) -> bool {
+        self.genes
+            .as_deref()
+            .is_some_and(|g| g.eq_ignore_ascii_case("all"))
+    }
+
+    pub fn gene_list(&self) -> Vec<String> {
+        self.genes
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|g| g.trim().to_string())
+            .filter(|g| !g.is_empty())
+            .collect()
+    }
+
+    /// Names given to `--exclude_genes`, read from the file when the value is
+    /// an existing path
+    pub fn excluded_genes(&self) -> std::io::Result<Vec<String>> {
+        let Some(exclude) = &self.exclude_genes else {
+            return Ok(Vec::new());
+        };
+        let list = if std::path::Path::new(exclude).is_file() {
+            std::fs::read_to_string(exclude)?
+        } else {
+            exclude.clone()
+        };
+
+        Ok(list
+            .split([',', '\n'])
+            .map(|g| g.trim().to_string())
+            .filter(|g| !g.is_empty())
+            .collect())
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct GraphArgs {
+    #[arg(
+        short = 'g',
+        long = "gfa",
+        help = "graph to generate probes from ('-' reads stdin)"
+    )]
+    pub gfa_path: String,
+
+    #[arg(
+        long = "min_paths",
+        default_value_t = 2,
+        help = "Minimum number of paths required before core segments are detected"
+    )]
+    pub min_paths: usize,
+
+    #[arg(
+        long = "include_ambiguous",
+        default_value_t = false,
+        help = "Also tile segments present in every path but repeated within some"
+    )]
+    pub include_ambiguous: bool,
+
+    #[arg(
+        long = "accessory",
+        default_value_t = false,
+        help = "Tile accessory (non-core) segments instead of core ones, for lineage-discriminating probes"
+    )]
+    pub accessory: bool,
+
+    #[arg(
+        long = "accessory_max_fraction",
+        default_value_t = 0.5,
+        help = "With --accessory, the largest fraction of paths a segment may appear in"
+    )]
+    pub accessory_max_fraction: f64,
+
+    #[arg(
+        long = "bubble_anchors",
+        default_value_t = false,
+        conflicts_with = "accessory",
+        help = "Tile only the ends of core segments that border variable bubbles (non-core segments), for genotyping probes"
+    )]
+    pub bubble_anchors: bool,
+
+    #[arg(
+        long = "anchor_window",
+        default_value_t = 150,
+        help = "With --bubble_anchors, how many bp of each core segment next to the bubble to tile"
+    )]
+    pub anchor_window: usize,
+
+    #[arg(
+        long = "path_coords",
+        default_value_t = false,
+        help = "Report each probe's coordinates in every path (P line) or sample walk (W line)"
+    )]
+    pub path_coords: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ProbesFromFastaArgs {
+    #[arg(
+        short = 'f',
+        long = "fasta",
+        help = "FASTA of target sequences, one record per target"
+    )]
+    pub in_fasta: String,
 }
 
 #[derive(Parser, Clone)]
@@ -102,7 +626,7 @@ pub struct KmerOptions {
     #[arg(
         short = 'b',
         long = "center_base",
-        help = "Center base leave blank to not consider a center_base"
+        help = "Base required at 0-based index kmer_size/2 (for even k, the first base of the second half), case-insensitive; leave blank to not consider a center_base"
     )]
     pub center_base: Option<char>,
 
@@ -135,6 +659,221 @@ pub struct KmerOptions {
         help = "skip GC filtering"
     )]
     pub skip_gc: bool,
+
+    #[arg(
+        long = "gc_exclude_junction",
+        action = ArgAction::SetTrue,
+        help = "With --center_base, leave the fixed junction base out of the half-GC values the GC filter uses"
+    )]
+    pub gc_exclude_junction: bool,
+
+    #[arg(
+        long = "max_homopolymer",
+        default_value_t = 5,
+        help = "Reject probes containing a poly-A or poly-T run of at least this length (0 = off)"
+    )]
+    pub max_homopolymer: usize,
+
+    #[arg(
+        long = "max_homopolymer_run",
+        help = "Reject probes whose longest run of any single base exceeds this length"
+    )]
+    pub max_homopolymer_run: Option<usize>,
+
+    #[arg(
+        long = "unique_only",
+        action = ArgAction::SetTrue,
+        help = "Keep only probes that occur at exactly one position in their target"
+    )]
+    pub unique_only: bool,
+
+    #[arg(
+        long = "max_gc_skew",
+        help = "Reject probes whose half-GC values differ by more than this many percentage points"
+    )]
+    pub max_gc_skew: Option<usize>,
+
+    #[arg(
+        long = "skip_masked",
+        action = ArgAction::SetTrue,
+        help = "Reject probes with any soft-masked (lowercase) base; needs --fasta_case preserve"
+    )]
+    pub skip_masked: bool,
+
+    #[arg(
+        long = "junction_window",
+        default_value_t = crate::core::probes::Probes::DEFAULT_JUNCTION_WINDOW,
+        help = "Bases centred on the junction base used for the junction GC/complexity metrics"
+    )]
+    pub junction_window: usize,
+
+    #[arg(
+        long = "min_junction_gc",
+        help = "Reject probes whose junction window has less than this GC percentage"
+    )]
+    pub min_junction_gc: Option<usize>,
+
+    #[arg(
+        long = "edge_trim",
+        default_value_t = 0,
+        help = "Skip windows within this many bp of either end of each target sequence"
+    )]
+    pub edge_trim: usize,
+
+    #[arg(
+        long = "allow_short",
+        action = ArgAction::SetTrue,
+        help = "Give targets shorter than the kmer size one probe: genes padded with reference context up to the kmer size, FASTA records and segments as a whole"
+    )]
+    pub allow_short: bool,
+
+    #[arg(
+        long = "kmer_min",
+        requires = "kmer_max",
+        help = "Shortest variable-length probe; with --kmer_max, each start takes the shortest length reaching --min_tm"
+    )]
+    pub kmer_min: Option<usize>,
+
+    #[arg(
+        long = "kmer_max",
+        requires = "kmer_min",
+        help = "Longest variable-length probe"
+    )]
+    pub kmer_max: Option<usize>,
+
+    #[arg(
+        long = "min_tm",
+        default_value_t = 70.0,
+        help = "Melting temperature (C) variable-length probes must reach"
+    )]
+    pub min_tm: f64,
+}
+
+impl KmerOptions {
+    /// `(min, max)` probe lengths when variable-length probes were requested
+    pub fn variable_length(&self) -> Option<(usize, usize)> {
+        self.kmer_min.zip(self.kmer_max)
+    }
+
+    /// Shortest probe that can be produced
+    pub fn min_probe_len(&self) -> usize {
+        self.kmer_min.unwrap_or(self.kmer_size)
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct OffTargetOptions {
+    #[arg(
+        short = 'i',
+        long = "off_target_directory",
+        global = true,
+        help = "Directory containing off-target FASTA/index files (repeat to search several)"
+    )]
+    pub off_target_directory: Vec<String>,
+
+    #[arg(
+        long = "off_target_fasta",
+        global = true,
+        help = "Off-target FASTA to index in memory and screen against, without writing a .cbl (repeatable)"
+    )]
+    pub off_target_fasta: Vec<String>,
+
+    #[arg(
+        long = "index_list",
+        global = true,
+        help = "File listing index directories or .cbl files to screen against, one per line (missing paths are skipped)"
+    )]
+    pub index_list: Option<String>,
+
+    #[arg(
+        long = "no_offtarget",
+        action = ArgAction::SetTrue,
+        global = true,
+        help = "Skip the off-target screen even when off-target indexes or FASTAs are given"
+    )]
+    pub no_offtarget: bool,
+
+    #[arg(
+        long = "max_hits",
+        default_value = "5",
+        value_delimiter = ',',
+        global = true,
+        help = "Maximum number of index hits allowed per kmer; a comma-separated list writes one probe set per threshold from a single query (the first is the main output)"
+    )]
+    pub max_hits: Vec<usize>,
+
+    #[arg(
+        long = "min_offtarget_genomes",
+        default_value_t = 1,
+        global = true,
+        help = "Only penalize probes that hit at least this many distinct off-target indexes"
+    )]
+    pub min_offtarget_genomes: usize,
+
+    #[arg(
+        long = "top_off_targets",
+        default_value_t = 5,
+        global = true,
+        help = "Number of most frequently hit off-target indexes to report per gene (0 = disable)"
+    )]
+    pub top_off_targets: usize,
+
+    #[arg(
+        long = "max_offtarget_identity",
+        global = true,
+        help = "Align every probe (12-mer seeds) against each index's source FASTA and count placements above this percent identity as hits"
+    )]
+    pub max_offtarget_identity: Option<f64>,
+
+    #[arg(
+        long = "clean_only",
+        action = ArgAction::SetTrue,
+        global = true,
+        help = "Keep only probes with no off-target hits at all"
+    )]
+    pub clean_only: bool,
+
+    #[arg(
+        long = "hit_matrix",
+        global = true,
+        help = "Write a probe x off-target index TSV of 0/1 hits for every screened probe (can be large)"
+    )]
+    pub hit_matrix: Option<String>,
+
+    #[arg(
+        long = "query_cache",
+        global = true,
+        help = "Record each finished off-target index query in this file, so a rerun skips indexes already searched"
+    )]
+    pub query_cache: Option<String>,
+
+    #[arg(
+        long = "specificity_weight",
+        default_value_t = 0.0,
+        global = true,
+        help = "Blend each probe's off-target specificity into its score with this weight (0-1, 0 = report only)"
+    )]
+    pub specificity_weight: f32,
+
+    #[arg(
+        long = "prefix_bits",
+        value_enum,
+        default_value_t = PrefixBits::P24,
+        global = true,
+        help = "CBL prefix bits to build indexes with; queries refuse indexes built with another value"
+    )]
+    pub prefix_bits: PrefixBits,
+}
+
+impl OffTargetOptions {
+    /// Whether probes will be screened: some index or FASTA is given and
+    /// `--no_offtarget` is not
+    pub fn screens(&self) -> bool {
+        !self.no_offtarget
+            && !(self.off_target_directory.is_empty()
+                && self.off_target_fasta.is_empty()
+                && self.index_list.is_none())
+    }
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -147,6 +886,47 @@ pub struct BuildArgs {
         help = "Use canonical kmers (default: true)"
     )]
     pub canonical: bool,
+
+    #[arg(
+        long = "build_batch",
+        default_value_t = 0,
+        help = "Maximum number of FASTA files indexed at once; each holds its full index in memory (0 = no limit)"
+    )]
+    pub build_batch: usize,
+
+    #[arg(
+        long = "compress_index",
+        action = ArgAction::SetTrue,
+        help = "Gzip index files (detected automatically when querying)"
+    )]
+    pub compress_index: bool,
+
+    #[arg(
+        long = "both_strands",
+        action = ArgAction::SetTrue,
+        conflicts_with = "canonical",
+        help = "Insert each sequence and its reverse complement, so queries check one orientation (builds a non-canonical index; cannot be combined with --canonical; larger indexes)"
+    )]
+    pub both_strands: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct StatsArgs {
+    #[arg(help = "First .cbl index")]
+    pub index_a: String,
+
+    #[arg(help = "Second .cbl index")]
+    pub index_b: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct AnnotateArgs {
+    #[arg(
+        short = 'f',
+        long = "fasta",
+        help = "Probe FASTA, e.g. from an earlier run"
+    )]
+    pub probes: String,
 }
 
 pub fn parse_args() -> Args {
@@ -1,6 +1,8 @@
+use crate::cli::KmerOptions;
+use crate::core::seq::{gc_sixths, melting_temperature, reverse_complement};
 use log::*;
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::Write;
 
@@ -9,6 +11,7 @@ pub type ProbeSet = Vec<Probes>;
 #[derive(Debug, Clone)]
 pub struct GeneKmers {
     pub gene: String,
+    pub seqid: String,
     pub start: u64,
     pub end: u64,
     pub kmers: ProbeSet, // type ProbeSet = Vec<Probes>
@@ -17,34 +20,57 @@ pub struct GeneKmers {
 }
 
 impl GeneKmers {
-    pub fn filter_kmers(
-        &self,
-        center_base: Option<char>,
-        min_gc: usize,
-        max_gc: usize,
-        skip_gc: bool,
-    ) -> GeneKmers {
+    pub fn filter_kmers(&self, options: &KmerOptions) -> GeneKmers {
+        let (min_gc, max_gc) = (options.min_gc, options.max_gc);
         let valid_kmers: Vec<Probes> = self
             .kmers
             .par_iter()
             .filter(|probe| {
-                let junction_matches = match center_base {
-                    Some(base) => probe.junction_base == base,
+                let junction_matches = match options.center_base {
+                    Some(base) => probe.junction_base == base.to_ascii_uppercase(),
                     None => true,
                 };
 
                 let first_gc_valid = min_gc <= probe.first_half_gc && probe.first_half_gc <= max_gc;
                 let second_gc_valid =
                     min_gc <= probe.second_half_gc && probe.second_half_gc <= max_gc;
-                let gc_valid = skip_gc || (first_gc_valid && second_gc_valid);
+                let gc_valid = options.skip_gc || (first_gc_valid && second_gc_valid);
+
+                let skew = probe.first_half_gc.abs_diff(probe.second_half_gc);
+                let skew_valid = !options.max_gc_skew.is_some_and(|max_skew| skew > max_skew);
+
+                // 0 disables the poly-A/poly-T check
+                let poly_at_valid =
+                    options.max_homopolymer == 0 || probe.poly_at_run < options.max_homopolymer;
+
+                let homopolymer_valid = !options
+                    .max_homopolymer_run
+                    .is_some_and(|max| probe.max_homopolymer_run > max);
 
-                junction_matches && gc_valid
+                let unique_valid = !options.unique_only || probe.locations.len() == 1;
+
+                let junction_gc_valid = !options
+                    .min_junction_gc
+                    .is_some_and(|min| probe.junction_gc < min);
+
+                let mask_valid =
+                    !options.skip_masked || !probe.kmer.bytes().any(|b| b.is_ascii_lowercase());
+
+                junction_matches
+                    && gc_valid
+                    && skew_valid
+                    && poly_at_valid
+                    && homopolymer_valid
+                    && unique_valid
+                    && junction_gc_valid
+                    && mask_valid
             })
             .cloned()
             .collect();
 
         GeneKmers {
             gene: self.gene.clone(),
+            seqid: self.seqid.clone(),
             start: self.start,
             end: self.end,
             kmers: valid_kmers,
@@ -53,57 +79,371 @@ impl GeneKmers {
         }
     }
 
-    pub fn log_kmers_with_coords(&self, kmer_size: usize) {
-        for probe in &self.kmers {
-            for &start in &probe.locations {
-                let end = if self.strand == "-" {
-                    if start >= kmer_size {
-                        start - kmer_size
-                    } else {
-                        0
-                    }
-                } else {
-                    start + kmer_size
-                };
+    /// Log the spread of half-GC values over this gene's candidate probes
+    /// and how many have both halves inside `min_gc..=max_gc`, to help tune
+    /// the GC window.
+    pub fn log_gc_distribution(&self, min_gc: usize, max_gc: usize) {
+        let mut halves: Vec<usize> = self
+            .kmers
+            .iter()
+            .flat_map(|p| [p.first_half_gc, p.second_half_gc])
+            .collect();
+        if halves.is_empty() {
+            return;
+        }
+        halves.sort_unstable();
+
+        let in_window = self
+            .kmers
+            .iter()
+            .filter(|p| {
+                (min_gc..=max_gc).contains(&p.first_half_gc)
+                    && (min_gc..=max_gc).contains(&p.second_half_gc)
+            })
+            .count();
+        info!(
+            gene = self.gene.as_str();
+            "Gene {}: half-GC of {} candidate(s) min {}%, median {}%, max {}%; {} with both halves in {}-{}%",
+            self.gene,
+            self.kmers.len(),
+            halves[0],
+            halves[halves.len() / 2],
+            halves[halves.len() - 1],
+            in_window,
+            min_gc,
+            max_gc
+        );
+    }
+
+    /// Record on every probe its sequence with up to `flank` bases of
+    /// `contig` (the gene's reference sequence) on each side, taken around its
+    /// first location, clamped at the contig ends and on the probe's strand.
+    pub fn add_flanks(&mut self, contig: &str, flank: usize) {
+        let minus = self.strand == "-";
+        for i in 0..self.kmers.len() {
+            let probe = &self.kmers[i];
+            let Some(&location) = probe.locations.iter().min() else {
+                continue;
+            };
+            let (start, end) = self.genomic_interval(location, probe.kmer.len());
+            let window = &contig
+                [start.saturating_sub(flank).min(contig.len())..(end + flank).min(contig.len())];
+            self.kmers[i].flanked = Some(if minus {
+                reverse_complement(window)
+            } else {
+                window.to_string()
+            });
+        }
+    }
+
+    /// 0-based half-open genomic intervals within the gene that no probe
+    /// location covers, in ascending order.
+    pub fn coverage_gaps(&self) -> Vec<(usize, usize)> {
+        let mut covered: Vec<(usize, usize)> = self
+            .kmers
+            .iter()
+            .flat_map(|p| {
+                p.locations
+                    .iter()
+                    .map(|&loc| self.genomic_interval(loc, p.kmer.len()))
+            })
+            .collect();
+        covered.sort_unstable();
+
+        let (gene_start, gene_end) = ((self.start as usize).saturating_sub(1), self.end as usize);
+        let mut gaps = Vec::new();
+        let mut cursor = gene_start;
+        for (start, end) in covered {
+            if start > cursor {
+                gaps.push((cursor, start.min(gene_end)));
+            }
+            cursor = cursor.max(end);
+            if cursor >= gene_end {
+                break;
+            }
+        }
+        if cursor < gene_end {
+            gaps.push((cursor, gene_end));
+        }
+        gaps.retain(|(start, end)| start < end);
+        gaps
+    }
 
-                info!("{},{},{}", probe.kmer, start, end);
+    /// Log `kmer,start,end` for every probe location as a 0-based half-open
+    /// genomic interval, whichever strand the gene is on.
+    pub fn log_kmers_with_coords(&self) {
+        for probe in &self.kmers {
+            for &location in &probe.locations {
+                let (start, end) = self.genomic_interval(location, probe.kmer.len());
+                info!(
+                    gene = self.gene.as_str(), kmer = probe.kmer.as_str();
+                    "{},{},{}", probe.kmer, start, end
+                );
             }
         }
     }
 
-    pub fn write_all_keys_to_file(&self, filename: String) {
+    /// Append this gene's probes to a FASTA file. With `revcomp` the sequence
+    /// line is the reverse complement (antisense probe) and the target k-mer
+    /// is kept in the header. Sequence lines are wrapped at `wrap` characters
+    /// (0 = no wrapping).
+    pub fn write_all_keys_to_file(&self, filename: String, revcomp: bool, wrap: usize) {
         let mut final_file = OpenOptions::new()
             .create(true) // Creates the file if it doesn't exist
             .append(true) // Appends to the file if it does exist
             .open(&filename)
             .expect("Failed to open or create FASTA file");
 
-        for (i, probe) in self.kmers.iter().enumerate() {
-            let coords_str = probe
-                .locations
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<_>>()
-                .join(",");
+        for i in 0..self.kmers.len() {
+            self.write_fasta_record(&mut final_file, i, revcomp, wrap);
+        }
+    }
+
+    /// Stable name of probe `i` (0-based): `<gene>_<first location>`. A
+    /// location holds one probe per target, so the name is unique there and
+    /// does not change with the probe's rank or with which other probes were
+    /// kept, letting runs be diffed and probes tracked across panel versions.
+    pub fn probe_id(&self, i: usize) -> String {
+        let first = self.kmers[i].locations.iter().min().copied().unwrap_or(0);
+        format!("{}_{}", self.gene, first)
+    }
+
+    /// Write probe `i` (0-based, named by `probe_id`) as one FASTA record.
+    pub fn write_fasta_record(&self, out: &mut impl Write, i: usize, revcomp: bool, wrap: usize) {
+        writeln!(out, ">{}", self.fasta_header(i, revcomp)).expect("Failed to write FASTA header");
+        self.write_fasta_sequence(out, i, revcomp, wrap);
+    }
 
+    /// FASTA header of probe `i`, without the leading `>`.
+    pub fn fasta_header(&self, i: usize, revcomp: bool) -> String {
+        let probe = &self.kmers[i];
+        let coords_str = probe
+            .locations
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let target = if revcomp {
+            format!(" target={}", probe.kmer)
+        } else {
+            String::new()
+        };
+        let flanked = probe
+            .flanked
+            .as_ref()
+            .map_or(String::new(), |f| format!(" flanked={}", f));
+        let specificity = probe
+            .specificity
+            .map_or(String::new(), |s| format!(" specificity={:.3}", s));
+        let conservation = probe
+            .conservation
+            .map_or(String::new(), |c| format!(" conservation={:.3}", c));
+
+        format!(
+            "{}    {} : {} copies{}{}{}{}",
+            self.probe_id(i),
+            coords_str,
+            probe.locations.len(),
+            target,
+            flanked,
+            specificity,
+            conservation
+        )
+    }
+
+    /// Sequence lines of probe `i`, wrapped at `wrap` bases (0 = one line).
+    pub fn write_fasta_sequence(&self, out: &mut impl Write, i: usize, revcomp: bool, wrap: usize) {
+        let probe = &self.kmers[i];
+        let sequence = if revcomp {
+            reverse_complement(&probe.kmer)
+        } else {
+            probe.kmer.clone()
+        };
+        let line_len = if wrap == 0 {
+            sequence.len().max(1)
+        } else {
+            wrap
+        };
+        for line in sequence.as_bytes().chunks(line_len) {
+            out.write_all(line)
+                .and_then(|_| writeln!(out))
+                .expect("Failed to write FASTA sequence");
+        }
+    }
+
+    /// 0-based half-open genomic interval covered by a probe of `length` at
+    /// `location` (minus-strand locations are the probe's 5' end).
+    pub fn genomic_interval(&self, location: usize, length: usize) -> (usize, usize) {
+        if self.strand == "-" {
+            ((location + 1).saturating_sub(length), location + 1)
+        } else {
+            (location, location + length)
+        }
+    }
+
+    /// Append this gene and its probes to a GFF3 file as a `gene` feature with
+    /// one `probe` child per probe location (1-based inclusive coordinates).
+    pub fn write_gff3_to_file(&self, filename: String) {
+        let mut final_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&filename)
+            .expect("Failed to open or create GFF3 file");
+
+        self.write_gff3_gene_record(&mut final_file);
+        for i in 0..self.kmers.len() {
+            self.write_gff3_probe_records(&mut final_file, i);
+        }
+    }
+
+    /// Write the `gene` feature that this gene's probe records point to.
+    pub fn write_gff3_gene_record(&self, out: &mut impl Write) {
+        writeln!(
+            out,
+            "{}\tvisiogen\tgene\t{}\t{}\t.\t{}\t.\tID={};Name={}",
+            self.seqid, self.start, self.end, self.strand, self.gene, self.gene
+        )
+        .expect("Failed to write GFF3 gene record");
+    }
+
+    /// Write one `probe` record per location of probe `i` (0-based).
+    pub fn write_gff3_probe_records(&self, out: &mut impl Write, i: usize) {
+        let probe = &self.kmers[i];
+        for (j, &location) in probe.locations.iter().enumerate() {
+            let (start, end) = self.genomic_interval(location, probe.kmer.len());
             writeln!(
-                final_file,
-                ">{}_{}    {} : {} copies",
+                out,
+                "{}\tvisiogen\tprobe\t{}\t{}\t{:.3}\t{}\t.\tID={}.{};Name={};Parent={};first_half_gc={};second_half_gc={};complexity={:.3};sequence={}{}{}{}{}",
+                self.seqid,
+                start + 1,
+                end,
+                probe.score,
+                self.strand,
+                self.probe_id(i),
+                j + 1,
+                self.probe_id(i),
                 self.gene,
-                i + 1,
-                coords_str,
-                probe.locations.len()
+                probe.first_half_gc,
+                probe.second_half_gc,
+                probe.complexity,
+                probe.kmer,
+                probe
+                    .flanked
+                    .as_ref()
+                    .map_or(String::new(), |f| format!(";flanked_sequence={}", f)),
+                probe
+                    .splice_junction
+                    .map_or(String::new(), |j| format!(";splice_junction={}", j)),
+                probe
+                    .specificity
+                    .map_or(String::new(), |s| format!(";specificity={:.3}", s)),
+                probe
+                    .conservation
+                    .map_or(String::new(), |c| format!(";conservation={:.3}", c))
             )
-            .expect("Failed to write FASTA header");
+            .expect("Failed to write GFF3 probe record");
+        }
+    }
+
+    /// Append one `gene,sequence,specificity` row per probe to a CSV file.
+    pub fn write_csv_to_file(&self, filename: String) {
+        let mut final_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&filename)
+            .expect("Failed to open or create CSV file");
 
-            writeln!(final_file, "{}", probe.kmer).expect("Failed to write FASTA sequence");
+        for i in 0..self.kmers.len() {
+            self.write_csv_row(&mut final_file, i);
         }
     }
 
-    pub fn log_and_write_kmers(&self, kmer_size: usize, filename: String) {
-        Self::write_all_keys_to_file(self, filename);
+    /// Write probe `i` (0-based) as one `gene,sequence,specificity` row
+    /// (specificity empty when not screened), plus the flanked sequence when set.
+    pub fn write_csv_row(&self, out: &mut impl Write, i: usize) {
+        let probe = &self.kmers[i];
+        let specificity = probe
+            .specificity
+            .map_or(String::new(), |s| format!("{:.3}", s));
+        let flanked = probe
+            .flanked
+            .as_ref()
+            .map_or(String::new(), |f| format!(",{}", csv_field(f)));
+        writeln!(
+            out,
+            "{},{},{}{}",
+            csv_field(&self.gene),
+            csv_field(&probe.kmer),
+            specificity,
+            flanked
+        )
+        .expect("Failed to write CSV row");
+    }
 
+    /// Append one JSON object per probe to a JSON-lines file.
+    pub fn write_jsonl_to_file(&self, filename: String) {
+        let mut final_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&filename)
+            .expect("Failed to open or create JSONL file");
+
+        for i in 0..self.kmers.len() {
+            self.write_jsonl_record(&mut final_file, i);
+        }
+    }
+
+    /// Write probe `i` (0-based) as one JSON object on its own line.
+    pub fn write_jsonl_record(&self, out: &mut impl Write, i: usize) {
+        let probe = &self.kmers[i];
+        let locations = probe
+            .locations
+            .iter()
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let off_target_hits = self.kmer_hits.get(&probe.kmer).map_or(0, Vec::len);
+
+        writeln!(
+            out,
+            "{{\"id\":{},\"gene\":{},\"seqid\":{},\"strand\":{},\"sequence\":{},\"locations\":[{}],\"first_half_gc\":{},\"second_half_gc\":{},\"complexity\":{:.4},\"poly_at_run\":{},\"max_homopolymer_run\":{},\"junction_base\":{},\"junction_gc\":{},\"junction_complexity\":{:.4},\"score\":{:.4},\"off_target_hits\":{}{}{}{}{}}}",
+            json_string(&self.probe_id(i)),
+            json_string(&self.gene),
+            json_string(&self.seqid),
+            json_string(&self.strand),
+            json_string(&probe.kmer),
+            locations,
+            probe.first_half_gc,
+            probe.second_half_gc,
+            probe.complexity,
+            probe.poly_at_run,
+            probe.max_homopolymer_run,
+            json_string(&probe.junction_base.to_string()),
+            probe.junction_gc,
+            probe.junction_complexity,
+            probe.score,
+            off_target_hits,
+            probe
+                .flanked
+                .as_ref()
+                .map_or(String::new(), |f| format!(",\"flanked_sequence\":{}", json_string(f))),
+            probe
+                .splice_junction
+                .map_or(String::new(), |j| format!(",\"splice_junction\":{}", j)),
+            probe
+                .specificity
+                .map_or(String::new(), |s| format!(",\"specificity\":{:.4}", s)),
+            probe
+                .conservation
+                .map_or(String::new(), |c| format!(",\"conservation\":{:.4}", c))
+        )
+        .expect("Failed to write JSONL record");
+    }
+
+    pub fn log_kmers(&self) {
         info!(
+            gene = self.gene.as_str();
             "Gene: {}, Strand: {}, Start: {}, End: {}, Total: {}",
             self.gene,
             self.strand,
@@ -113,22 +453,149 @@ impl GeneKmers {
         );
 
         if log_enabled!(Level::Debug) {
-            self.log_kmers_with_coords(kmer_size);
+            self.log_kmers_with_coords();
+        }
+    }
+
+    /// Copy of this gene with a different probe set.
+    pub fn with_kmers(&self, kmers: ProbeSet) -> GeneKmers {
+        GeneKmers {
+            gene: self.gene.clone(),
+            seqid: self.seqid.clone(),
+            start: self.start,
+            end: self.end,
+            kmers,
+            strand: self.strand.clone(),
+            kmer_hits: self.kmer_hits.clone(),
         }
     }
 
+    /// Offset of a genomic `location` from the 5' end of the gene on its own
+    /// strand (minus-strand locations count down from `end`).
+    pub fn relative_offset(&self, location: usize) -> usize {
+        if self.strand == "-" {
+            (self.end as usize - 1).saturating_sub(location)
+        } else {
+            location.saturating_sub(self.start as usize - 1)
+        }
+    }
+
+    /// Probes at offsets `0, k, 2k, ...`, i.e. end-to-end windows with no
+    /// overlaps or gaps, in positional order. A sequence repeated at several
+    /// window offsets is kept once.
+    pub fn contiguous_tiles(&self, kmer_size: usize) -> GeneKmers {
+        let mut by_offset: BTreeMap<usize, &Probes> = BTreeMap::new();
+        for probe in &self.kmers {
+            for &loc in &probe.locations {
+                let offset = self.relative_offset(loc);
+                if offset % kmer_size == 0 {
+                    by_offset.insert(offset, probe);
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let tiles = by_offset
+            .into_values()
+            .filter(|probe| seen.insert(probe.kmer.as_str()))
+            .cloned()
+            .collect();
+
+        self.with_kmers(tiles)
+    }
+
+    /// Aggregates `kmer_hits` across all probes of this gene and returns the
+    /// off-target index files ordered by how many probes hit them (most first).
+    #[cfg(feature = "index")]
+    pub fn off_target_ranking(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for files in self.kmer_hits.values() {
+            for file in files {
+                *counts.entry(file.as_str()).or_insert(0) += 1;
+            }
+        }
+
+        let mut ranking: Vec<(String, usize)> = counts
+            .into_iter()
+            .map(|(file, count)| (file.to_string(), count))
+            .collect();
+        ranking.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranking
+    }
+
+    /// Returns a copy keeping the `n` highest scoring probes that start in
+    /// each consecutive `window`-bp stretch of the gene (by offset from its
+    /// 5' end), for redundant coverage of every region.
+    pub fn best_per_window(&self, window: usize, n: usize) -> GeneKmers {
+        let mut by_window: BTreeMap<usize, Vec<&Probes>> = BTreeMap::new();
+        for probe in &self.kmers {
+            let Some(offset) = probe
+                .locations
+                .iter()
+                .map(|&loc| self.relative_offset(loc))
+                .min()
+            else {
+                continue;
+            };
+            by_window
+                .entry(offset / window.max(1))
+                .or_default()
+                .push(probe);
+        }
+
+        let kept = by_window
+            .into_values()
+            .flat_map(|mut probes| {
+                probes.sort_by(|a, b| best_first(a, b));
+                probes.into_iter().take(n).cloned()
+            })
+            .collect();
+        self.with_kmers(kept)
+    }
+
+    /// Returns a copy holding the `n` highest scoring probes (all probes when
+    /// `n == 0`). Ties on score are broken by lowest first location, then by
+    /// sequence, so the selection is the same on every run.
     pub fn best_probes(&self, n: u16) -> GeneKmers {
+        self.top_probes(n, best_first)
+    }
+
+    /// Keep the `n` probes whose Tm is closest to `tm_target` (ties broken
+    /// by score), so probes selected across genes share a similar Tm.
+    pub fn closest_tm_probes(&self, tm_target: f64, n: u16) -> GeneKmers {
+        // Tm is computed once per probe rather than on every comparison
+        let mut by_distance: Vec<(f64, &Probes)> = self
+            .kmers
+            .iter()
+            .map(|p| ((melting_temperature(&p.kmer) - tm_target).abs(), p))
+            .collect();
+        by_distance.sort_by(|(da, a), (db, b)| da.total_cmp(db).then_with(|| best_first(a, b)));
+        let sorted_probes = by_distance.into_iter().map(|(_, p)| p.clone()).collect();
+        self.first_probes(sorted_probes, n)
+    }
+
+    fn top_probes(
+        &self,
+        n: u16,
+        order: impl FnMut(&Probes, &Probes) -> std::cmp::Ordering,
+    ) -> GeneKmers {
         let mut sorted_probes = self.kmers.clone();
-        sorted_probes.sort_by(|a, b| {
-            b.score
-                .partial_cmp(&a.score)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
+        sorted_probes.sort_by(order);
+        self.first_probes(sorted_probes, n)
+    }
 
-        let best_probes = sorted_probes.into_iter().take(n as usize).collect();
+    /// This gene with the first `n` of `sorted_probes` (all of them for 0).
+    fn first_probes(&self, sorted_probes: Vec<Probes>, n: u16) -> GeneKmers {
+        let keep = if n == 0 {
+            sorted_probes.len()
+        } else {
+            n as usize
+        };
+        let best_probes = sorted_probes.into_iter().take(keep).collect();
 
         GeneKmers {
             gene: self.gene.clone(),
+            seqid: self.seqid.clone(),
             start: self.start,
             end: self.end,
             kmers: best_probes,
@@ -138,6 +605,191 @@ impl GeneKmers {
     }
 }
 
+/// Score order used for selection: highest score first, ties broken by
+/// lowest first location, then by sequence.
+fn best_first(a: &Probes, b: &Probes) -> std::cmp::Ordering {
+    b.score
+        .partial_cmp(&a.score)
+        .unwrap_or(std::cmp::Ordering::Equal)
+        .then_with(|| a.locations.iter().min().cmp(&b.locations.iter().min()))
+        .then_with(|| a.kmer.cmp(&b.kmer))
+}
+
+/// A probe FASTA header as written by `write_fasta_record`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeHeader {
+    /// Target the probe was designed for (the name without its `_<loc>`)
+    pub gene: String,
+    pub locations: Vec<usize>,
+    /// Probe target sequence from ` target=`, when the record holds its
+    /// reverse complement (`--output_revcomp`)
+    pub target: Option<String>,
+}
+
+/// Parse `<gene>_<loc>    <loc>,<loc> : <c> copies[ target=<seq>]...`. Names
+/// without a numeric suffix are taken whole as the gene, and a missing or
+/// unreadable location list gives no locations, so foreign FASTAs still
+/// parse.
+pub fn parse_probe_header(header: &str) -> ProbeHeader {
+    let mut fields = header.split_whitespace();
+    let name = fields.next().unwrap_or_default();
+    let gene = match name.rsplit_once('_') {
+        Some((gene, n)) if !gene.is_empty() && n.parse::<usize>().is_ok() => gene,
+        _ => name,
+    }
+    .to_string();
+
+    let mut rest = fields.peekable();
+    let locations = rest
+        .peek()
+        .and_then(|field| {
+            field
+                .split(',')
+                .map(|loc| loc.parse::<usize>().ok())
+                .collect::<Option<Vec<_>>>()
+        })
+        .unwrap_or_default();
+    let target = rest
+        .find_map(|field| field.strip_prefix("target="))
+        .map(str::to_string);
+
+    ProbeHeader {
+        gene,
+        locations,
+        target,
+    }
+}
+
+/// Map each distinct kmer to the indices (into `gene_kmers`) of every gene
+/// whose probe set contains it.
+pub fn kmer_gene_index(gene_kmers: &[GeneKmers]) -> HashMap<String, Vec<usize>> {
+    let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, gk) in gene_kmers.iter().enumerate() {
+        for probe in &gk.kmers {
+            let genes = index.entry(probe.kmer.clone()).or_default();
+            if genes.last() != Some(&i) {
+                genes.push(i);
+            }
+        }
+    }
+    index
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or newline.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Quote a string as a JSON string literal.
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Relative weights of the factors combined into a probe's score, normalised
+/// to sum to 1. Parsed from `gc=0.3,complexity=0.4,...`; omitted factors get
+/// no weight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreWeights {
+    /// Closeness of both half-GC values to 60%
+    pub gc: f32,
+    /// Lack of long single-base runs
+    pub complexity: f32,
+    /// G/C clamp: one to three G/C among the last five bases
+    pub three_prime_gc: f32,
+    /// Penalty for k-mers occurring more than once in the target
+    pub copy_number: f32,
+    /// Lack of long single-base runs in the window around the junction base
+    pub junction: f32,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            gc: 0.5,
+            complexity: 0.5,
+            three_prime_gc: 0.0,
+            copy_number: 0.0,
+            junction: 0.0,
+        }
+    }
+}
+
+impl std::str::FromStr for ScoreWeights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = Self {
+            gc: 0.0,
+            complexity: 0.0,
+            three_prime_gc: 0.0,
+            copy_number: 0.0,
+            junction: 0.0,
+        };
+
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected factor=weight, got '{}'", pair))?;
+            let value: f32 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid weight '{}' for {}", value, key))?;
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("weight for {} must be a non-negative number", key));
+            }
+            match key.trim() {
+                "gc" => weights.gc = value,
+                "complexity" => weights.complexity = value,
+                "gc3" | "three_prime_gc" => weights.three_prime_gc = value,
+                "copies" | "copy_number" => weights.copy_number = value,
+                "junction" => weights.junction = value,
+                other => {
+                    let expected = "gc, complexity, gc3, copies or junction";
+                    return Err(format!(
+                        "unknown score factor '{}' (expected {})",
+                        other, expected
+                    ));
+                }
+            }
+        }
+
+        let total = weights.gc
+            + weights.complexity
+            + weights.three_prime_gc
+            + weights.copy_number
+            + weights.junction;
+        if total <= 0.0 {
+            return Err("at least one score weight must be positive".to_string());
+        }
+
+        Ok(Self {
+            gc: weights.gc / total,
+            complexity: weights.complexity / total,
+            three_prime_gc: weights.three_prime_gc / total,
+            copy_number: weights.copy_number / total,
+            junction: weights.junction / total,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Probes {
     pub kmer: String,
@@ -145,49 +797,237 @@ pub struct Probes {
     pub first_half_gc: usize,
     pub second_half_gc: usize,
     pub complexity: f32,
+    pub poly_at_run: usize,
+    /// Longest run of any single base
+    pub max_homopolymer_run: usize,
     pub junction_base: char,
+    /// GC percentage of the window centred on the junction base
+    pub junction_gc: usize,
+    /// 0.0 (one repeated base) to 1.0 (no runs) within the junction window
+    pub junction_complexity: f32,
     pub score: f32,
+    /// Probe plus genomic context on each side, set by `--flank`
+    pub flanked: Option<String>,
+    /// 1-based transcript position of the first base after the exon-exon
+    /// junction the probe spans, set by `--exon_junctions`
+    pub splice_junction: Option<usize>,
+    /// `1 / (1 + distinct off-target indexes hit)`, set by the off-target
+    /// query
+    pub specificity: Option<f32>,
+    /// Fraction of targets, its own plus each `--pan_targets` FASTA, the
+    /// probe is found in (either strand), set by the pan-target screen
+    pub conservation: Option<f32>,
 }
 
 impl Probes {
-    fn new(kmer: String, locations: Vec<usize>) -> Self {
+    /// Bases around the junction measured by default (`--junction_window`)
+    pub const DEFAULT_JUNCTION_WINDOW: usize = 10;
+
+    pub fn new(kmer: String, locations: Vec<usize>) -> Self {
         let first_half_gc = Self::calculate_gc(&kmer[..kmer.len() / 2]);
         let second_half_gc = Self::calculate_gc(&kmer[kmer.len() / 2..]);
         let complexity = Self::score_homopolymer_repeats(&kmer);
-        let junction_base = kmer.chars().nth(24).unwrap_or('N');
-        let score = Self::calculate_probe_score(first_half_gc, second_half_gc, complexity);
-
-        Self {
+        let max_homopolymer_run = Self::longest_run(&kmer, |_| true);
+        let poly_at_run = Self::longest_poly_at_run(&kmer);
+        // Soft-masked (lowercase) bases are reported and matched as uppercase
+        let junction_base = kmer
+            .chars()
+            .nth(Self::junction_index(kmer.len()))
+            .map_or('N', |c| c.to_ascii_uppercase());
+        let mut probe = Self {
             kmer,
             locations,
             first_half_gc,
             second_half_gc,
             complexity,
+            poly_at_run,
+            max_homopolymer_run,
             junction_base,
-            score,
-        }
+            junction_gc: 0,
+            junction_complexity: 0.0,
+            score: 0.0,
+            flanked: None,
+            splice_junction: None,
+            specificity: None,
+            conservation: None,
+        };
+        probe.measure_junction(Self::DEFAULT_JUNCTION_WINDOW);
+        probe.rescore(&ScoreWeights::default());
+        probe
     }
 
-    fn calculate_probe_score(first_half_gc: usize, second_half_gc: usize, complexity: f32) -> f32 {
+    /// Recompute the junction metrics over `window` bases centred on the
+    /// junction base (clamped to the probe).
+    pub fn measure_junction(&mut self, window: usize) {
+        let len = self.kmer.len();
+        let window = window.clamp(1, len.max(1));
+        let start = Self::junction_index(len)
+            .saturating_sub(window / 2)
+            .min(len - window.min(len));
+        let region = &self.kmer[start..(start + window).min(len)];
+
+        self.junction_gc = Self::calculate_gc(region);
+        self.junction_complexity = Self::score_homopolymer_repeats(region);
+    }
+
+    /// Recompute the half-GC values; with `exclude_junction` the second half
+    /// starts after the junction base, which `--center_base` fixes rather
+    /// than leaving to design.
+    pub fn measure_half_gc(&mut self, exclude_junction: bool) {
+        let len = self.kmer.len();
+        let junction = Self::junction_index(len);
+        let second_start = if exclude_junction {
+            (junction + 1).min(len)
+        } else {
+            junction
+        };
+        self.first_half_gc = Self::calculate_gc(&self.kmer[..junction]);
+        self.second_half_gc = Self::calculate_gc(&self.kmer[second_start..]);
+    }
+
+    /// Recompute `score` (0 to 1) as the weighted sum of the score factors.
+    pub fn rescore(&mut self, weights: &ScoreWeights) {
+        let score = weights.gc * Self::gc_balance_score(self.first_half_gc, self.second_half_gc)
+            + weights.complexity * self.complexity
+            + weights.three_prime_gc * Self::three_prime_gc_score(&self.kmer)
+            + weights.copy_number / self.locations.len().max(1) as f32
+            + weights.junction * self.junction_complexity;
+        self.score = score.clamp(0.0, 1.0);
+    }
+
+    /// 0-based index of the junction (center) base for a k-mer of length `k`.
+    /// For odd k this is the true middle base; for even k it is the first base
+    /// of the second half, matching the split used for the half-GC values.
+    pub fn junction_index(k: usize) -> usize {
+        k / 2
+    }
+
+    fn gc_balance_score(first_half_gc: usize, second_half_gc: usize) -> f32 {
         // Ideal GC content is around 60 ADD AS PARAM
         let ideal_gc = 60.0;
         let first_half_gc_score = 1.0 - (first_half_gc as f32 - ideal_gc).abs() / ideal_gc;
         let second_half_gc_score = 1.0 - (second_half_gc as f32 - ideal_gc).abs() / ideal_gc;
 
-        let gc_score = (first_half_gc_score + second_half_gc_score) / 2.0;
+        (first_half_gc_score + second_half_gc_score) / 2.0
+    }
+
+    /// 1.0 for a G/C clamp of three G/C among the last five bases, falling off
+    /// with fewer (weak 3' end) or more (overly sticky 3' end).
+    fn three_prime_gc_score(kmer: &str) -> f32 {
+        let tail = &kmer[kmer.len().saturating_sub(5)..];
+        let gc = tail
+            .chars()
+            .filter(|c| matches!(c, 'G' | 'g' | 'C' | 'c'))
+            .count();
+        if gc <= 3 {
+            gc as f32 / 3.0
+        } else {
+            (6 - gc) as f32 / 3.0
+        }
+    }
+
+    /// Tile `seq` as configured: fixed-length windows of `kmer_size`, or
+    /// variable-length probes when `--kmer_min`/`--kmer_max` are given.
+    pub fn tile(seq: &str, options: &KmerOptions, start_offset: usize) -> ProbeSet {
+        let mut probes = match options.variable_length() {
+            Some((min_len, max_len)) => Self::generate_variable_probes(
+                seq,
+                min_len,
+                max_len,
+                options.min_tm,
+                start_offset,
+                options.edge_trim,
+            ),
+            None => Self::generate_probes(seq, options.kmer_size, start_offset, options.edge_trim),
+        };
+        Self::measure_as_configured(&mut probes, options);
+        probes
+    }
+
+    /// One probe covering all of `seq`, for a target too short to tile
+    /// (`--allow_short`).
+    pub fn whole(seq: &str, options: &KmerOptions, start_offset: usize) -> ProbeSet {
+        let mut probes = vec![Self::new(seq.to_string(), vec![start_offset])];
+        Self::measure_as_configured(&mut probes, options);
+        probes
+    }
 
-        let complexity_score = complexity;
+    /// Redo the metrics whose settings differ from those `new` assumes.
+    fn measure_as_configured(probes: &mut [Probes], options: &KmerOptions) {
+        if options.junction_window != Self::DEFAULT_JUNCTION_WINDOW {
+            for probe in probes.iter_mut() {
+                probe.measure_junction(options.junction_window);
+            }
+        }
+        if options.gc_exclude_junction && options.center_base.is_some() {
+            for probe in probes.iter_mut() {
+                probe.measure_half_gc(true);
+                probe.rescore(&ScoreWeights::default());
+            }
+        }
+    }
+
+    /// For each start position, the shortest probe of `min_len..=max_len`
+    /// bases whose melting temperature reaches `min_tm`. Positions where no
+    /// length reaches it are skipped. Trimming works as in `generate_probes`.
+    pub fn generate_variable_probes(
+        seq: &str,
+        min_len: usize,
+        max_len: usize,
+        min_tm: f64,
+        start_offset: usize,
+        edge_trim: usize,
+    ) -> ProbeSet {
+        let mut kmers: HashMap<String, Vec<usize>> = HashMap::new();
 
-        // Weight GC content and complexity equally
-        let final_score = (gc_score * 0.5) + (complexity_score * 0.5);
+        let usable_end = seq.len().saturating_sub(edge_trim);
+        if usable_end < edge_trim + min_len {
+            return ProbeSet::new();
+        }
 
-        final_score.max(0.0).min(1.0) // Ensure score is between 0 and 1
+        for i in edge_trim..=usable_end - min_len {
+            let longest = max_len.min(usable_end - i);
+            if let Some(len) =
+                (min_len..=longest).find(|&len| melting_temperature(&seq[i..i + len]) >= min_tm)
+            {
+                kmers
+                    .entry(seq[i..i + len].to_string())
+                    .or_default()
+                    .push(i + start_offset);
+            }
+        }
+
+        Self::in_location_order(kmers)
+    }
+
+    /// Probes from a map of k-mer to locations, ordered by first location so
+    /// the candidates (and everything drawn from them) are the same each run.
+    fn in_location_order(kmers: HashMap<String, Vec<usize>>) -> ProbeSet {
+        let mut probes: ProbeSet = kmers
+            .into_iter()
+            .map(|(kmer, locations)| Self::new(kmer, locations))
+            .collect();
+        probes.sort_by_key(|probe| probe.locations.iter().min().copied());
+        probes
     }
 
-    pub fn generate_probes(seq: &str, kmer_size: usize, start_offset: usize) -> ProbeSet {
+    /// Tile every `kmer_size` window of `seq`, recording window starts plus
+    /// `start_offset`. Windows overlapping the first or last `edge_trim` bases
+    /// are skipped; a sequence with no room for a window yields no probes.
+    pub fn generate_probes(
+        seq: &str,
+        kmer_size: usize,
+        start_offset: usize,
+        edge_trim: usize,
+    ) -> ProbeSet {
         let mut kmers: HashMap<String, Vec<usize>> = HashMap::new();
 
-        for i in 0..=seq.len() - kmer_size {
+        let usable_end = seq.len().saturating_sub(edge_trim);
+        if usable_end < edge_trim + kmer_size {
+            return ProbeSet::new();
+        }
+
+        for i in edge_trim..=usable_end - kmer_size {
             let kmer = &seq[i..i + kmer_size];
             kmers
                 .entry(kmer.to_string())
@@ -195,43 +1035,255 @@ impl Probes {
                 .push(i + start_offset);
         }
 
-        kmers
-            .into_iter()
-            .map(|(kmer, locations)| Self::new(kmer, locations))
-            .collect()
+        Self::in_location_order(kmers)
     }
 
+    /// GC percentage (rounded down), IUPAC-aware: ambiguity codes count by
+    /// the share of their bases that are G or C (see `gc_sixths`), and N
+    /// is left out of the denominator. 0 when no base is informative.
     fn calculate_gc(sequence: &str) -> usize {
-        let total_length = sequence.len();
-        let gc_count = sequence
-            .chars()
-            .filter(|&c| c == 'G' || c == 'g' || c == 'C' || c == 'c')
-            .count();
-
-        let gc_content_percentage = (gc_count * 100) / total_length;
+        let (gc_sixths, counted) = sequence
+            .bytes()
+            .filter_map(gc_sixths)
+            .fold((0, 0), |(gc, n), weight| (gc + weight, n + 1));
 
-        gc_content_percentage
+        if counted == 0 {
+            return 0;
+        }
+        (gc_sixths * 100) / (counted * 6)
     }
 
-    /// Returns a complexity score between 0.0 (very repetitive) and 1.0 (diverse)
-    fn score_homopolymer_repeats(seq: &str) -> f32 {
-        let mut max_run = 1;
-        let mut current_run = 1;
+    /// Length of the longest run of one repeated character among those
+    /// accepted by `include` (0 if none are).
+    fn longest_run(seq: &str, include: impl Fn(char) -> bool) -> usize {
+        let mut max_run = 0;
+        let mut current_run = 0;
         let mut prev_char = None;
 
         for c in seq.chars() {
+            if !include(c) {
+                current_run = 0;
+                prev_char = None;
+                continue;
+            }
             if Some(c) == prev_char {
                 current_run += 1;
-                max_run = max_run.max(current_run);
             } else {
                 current_run = 1;
             }
+            max_run = max_run.max(current_run);
             prev_char = Some(c);
         }
 
+        max_run
+    }
+
+    /// Longest poly-A or poly-T stretch, ignoring case.
+    fn longest_poly_at_run(seq: &str) -> usize {
+        Self::longest_run(&seq.to_ascii_uppercase(), |c| c == 'A' || c == 'T')
+    }
+
+    /// Returns a complexity score between 0.0 (very repetitive) and 1.0 (diverse)
+    fn score_homopolymer_repeats(seq: &str) -> f32 {
+        let max_run = Self::longest_run(seq, |_| true).max(1);
+
         let length = seq.len() as f32;
         let repeat_fraction = max_run as f32 / length;
 
         1.0 - repeat_fraction
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn options(args: &[&str]) -> KmerOptions {
+        KmerOptions::parse_from(std::iter::once("visiogen").chain(args.iter().copied()))
+    }
+
+    fn gene_with(kmers: &[&str]) -> GeneKmers {
+        GeneKmers {
+            gene: "g1".to_string(),
+            seqid: "chr1".to_string(),
+            start: 1,
+            end: 1000,
+            kmers: kmers
+                .iter()
+                .enumerate()
+                .map(|(i, kmer)| Probes::new(kmer.to_string(), vec![i * 10]))
+                .collect(),
+            strand: "+".to_string(),
+            kmer_hits: HashMap::new(),
+        }
+    }
+
+    fn kept(gk: &GeneKmers) -> Vec<&str> {
+        gk.kmers.iter().map(|p| p.kmer.as_str()).collect()
+    }
+
+    #[test]
+    fn junction_index_is_the_middle_base_for_odd_k() {
+        assert_eq!(Probes::junction_index(5), 2);
+        assert_eq!(Probes::new("ACGTA".to_string(), vec![0]).junction_base, 'G');
+    }
+
+    #[test]
+    fn junction_index_is_the_first_base_of_the_second_half_for_even_k() {
+        assert_eq!(Probes::junction_index(6), 3);
+        assert_eq!(
+            Probes::new("ACGTTA".to_string(), vec![0]).junction_base,
+            'T'
+        );
+    }
+
+    #[test]
+    fn max_homopolymer_rejects_poly_a_and_poly_t_runs_only() {
+        let gk = gene_with(&["ACGTA", "AAAAC", "TTTTC", "GGGGC"]);
+        let filtered = gk.filter_kmers(&options(&[
+            "-k",
+            "5",
+            "--skip_gc",
+            "--max_homopolymer",
+            "4",
+        ]));
+        assert_eq!(kept(&filtered), ["ACGTA", "GGGGC"]);
+        let filtered = gk.filter_kmers(&options(&[
+            "-k",
+            "5",
+            "--skip_gc",
+            "--max_homopolymer",
+            "0",
+        ]));
+        assert_eq!(kept(&filtered).len(), 4);
+    }
+
+    #[test]
+    fn max_homopolymer_run_rejects_longer_runs_of_any_base() {
+        let gk = gene_with(&["ACGTA", "AGGGA", "ACCCC", "AATTA"]);
+        let filtered = gk.filter_kmers(&options(&["-k", "5", "--skip_gc"]));
+        assert_eq!(kept(&filtered).len(), 4);
+        let filtered = gk.filter_kmers(&options(&[
+            "-k",
+            "5",
+            "--skip_gc",
+            "--max_homopolymer_run",
+            "3",
+        ]));
+        assert_eq!(kept(&filtered), ["ACGTA", "AGGGA", "AATTA"]);
+    }
+
+    #[test]
+    fn center_base_filter_uses_the_junction_index() {
+        let gk = gene_with(&["AAGAA", "AAAGA", "AACTAA", "AAACAA"]);
+        let filtered = gk.filter_kmers(&options(&["-k", "5", "--skip_gc", "-b", "G"]));
+        assert_eq!(kept(&filtered), ["AAGAA"]);
+        let filtered = gk.filter_kmers(&options(&["-k", "6", "--skip_gc", "-b", "T"]));
+        assert_eq!(kept(&filtered), ["AACTAA"]);
+    }
+
+    #[test]
+    fn center_base_filter_ignores_case() {
+        let gk = gene_with(&["AAgAA", "ACaCA"]);
+        assert_eq!(gk.kmers[0].junction_base, 'G');
+        let filtered = gk.filter_kmers(&options(&["-k", "5", "--skip_gc", "-b", "G"]));
+        assert_eq!(kept(&filtered), ["AAgAA"]);
+        let filtered = gk.filter_kmers(&options(&["-k", "5", "--skip_gc", "-b", "a"]));
+        assert_eq!(kept(&filtered), ["ACaCA"]);
+    }
+
+    #[test]
+    fn gc_counts_strong_and_weak_codes() {
+        assert_eq!(Probes::calculate_gc("SSSS"), 100);
+        assert_eq!(Probes::calculate_gc("WWWW"), 0);
+        assert_eq!(Probes::calculate_gc("SWsw"), 50);
+        assert_eq!(Probes::calculate_gc("GCRA"), 62);
+    }
+
+    #[test]
+    fn gc_leaves_n_out_of_the_denominator() {
+        assert_eq!(Probes::calculate_gc("SSNN"), 100);
+        assert_eq!(Probes::calculate_gc("GANN"), 50);
+        assert_eq!(Probes::calculate_gc("NNNN"), 0);
+    }
+
+    #[test]
+    fn gc_exclude_junction_leaves_the_center_base_out_of_the_second_half() {
+        let half_gc = |args: &[&str]| {
+            let probes = Probes::tile("AAGCA", &options(args), 0);
+            let probe = probes.into_iter().next().unwrap();
+            (probe.first_half_gc, probe.second_half_gc)
+        };
+        assert_eq!(half_gc(&["-k", "5", "-b", "G"]), (0, 66));
+        assert_eq!(
+            half_gc(&["-k", "5", "-b", "G", "--gc_exclude_junction"]),
+            (0, 50)
+        );
+        // Without a center base there is no fixed base to leave out
+        assert_eq!(half_gc(&["-k", "5", "--gc_exclude_junction"]), (0, 66));
+    }
+
+    /// A gene whose i-th probe sits at location i with the i-th score.
+    fn scored(scores: &[f32]) -> GeneKmers {
+        let kmers = ["ACGTA", "CCGTA", "GCGTA", "TCGTA"];
+        let mut gk = gene_with(&kmers[..scores.len()]);
+        for (i, (probe, &score)) in gk.kmers.iter_mut().zip(scores).enumerate() {
+            probe.locations = vec![i];
+            probe.score = score;
+        }
+        gk
+    }
+
+    fn scores(gk: &GeneKmers) -> Vec<f32> {
+        gk.kmers.iter().map(|p| p.score).collect()
+    }
+
+    #[test]
+    fn closest_tm_probes_orders_by_tm_distance_then_score() {
+        let gk = scored(&[0.2, 0.9, 0.5, 0.7]);
+        let target = melting_temperature("CCGTA");
+        let distance = |p: &Probes| (melting_temperature(&p.kmer) - target).abs();
+        let mut expected = gk.kmers.clone();
+        expected.sort_by(|a, b| {
+            distance(a)
+                .total_cmp(&distance(b))
+                .then_with(|| best_first(a, b))
+        });
+
+        let closest = gk.closest_tm_probes(target, 2);
+        assert_eq!(kept(&closest), kept(&gk.with_kmers(expected))[..2]);
+        assert_eq!(closest.kmers[0].kmer, "CCGTA");
+    }
+
+    #[test]
+    fn best_probes_keeps_the_top_n_by_score() {
+        let gk = scored(&[0.2, 0.9, 0.5, 0.7]);
+        assert_eq!(scores(&gk.best_probes(2)), [0.9, 0.7]);
+    }
+
+    #[test]
+    fn best_probes_returns_all_when_fewer_than_n() {
+        let gk = scored(&[0.2, 0.9, 0.5]);
+        assert_eq!(scores(&gk.best_probes(10)), [0.9, 0.5, 0.2]);
+    }
+
+    #[test]
+    fn best_probes_zero_keeps_every_probe_in_score_order() {
+        let gk = scored(&[0.2, 0.9, 0.5]);
+        assert_eq!(scores(&gk.best_probes(0)), [0.9, 0.5, 0.2]);
+        assert!(scored(&[]).best_probes(0).kmers.is_empty());
+    }
+
+    #[test]
+    fn best_probes_breaks_score_ties_by_lowest_location() {
+        let gk = scored(&[0.5, 0.5, 0.5]);
+        let locations: Vec<usize> = gk
+            .best_probes(2)
+            .kmers
+            .iter()
+            .map(|p| p.locations[0])
+            .collect();
+        assert_eq!(locations, [0, 1]);
+    }
+}
@@ -1,32 +1,173 @@
-use bio::alphabets::dna::revcomp;
-
 use crate::core::probes::ProbeSet;
 
+/// Complement of a single IUPAC nucleotide code, preserving case. Ambiguity
+/// codes map to the code for the complementary set (R <-> Y, K <-> M,
+/// B <-> V, D <-> H; S, W and N are their own complement). Anything else is
+/// returned unchanged.
+pub fn complement_iupac(base: u8) -> u8 {
+    let complement = match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'T' | b'U' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        b'S' | b'W' | b'N' => base.to_ascii_uppercase(),
+        _ => return base,
+    };
+
+    if base.is_ascii_lowercase() {
+        complement.to_ascii_lowercase()
+    } else {
+        complement
+    }
+}
+
+/// Expected G/C share of an IUPAC nucleotide code, in sixths so every
+/// ambiguity weight is exact: the fraction of the bases it stands for that
+/// are G or C (S = 6, W = 0, R/Y/K/M = 3, B/V = 4, D/H = 2). `None` for N
+/// and anything that is not a nucleotide, which carries no GC information.
+pub fn gc_sixths(base: u8) -> Option<usize> {
+    match base.to_ascii_uppercase() {
+        b'G' | b'C' | b'S' => Some(6),
+        b'A' | b'T' | b'U' | b'W' => Some(0),
+        b'R' | b'Y' | b'K' | b'M' => Some(3),
+        b'B' | b'V' => Some(4),
+        b'D' | b'H' => Some(2),
+        _ => None,
+    }
+}
+
+/// Melting temperature in °C by the basic GC-content method: the Wallace
+/// rule (2 per A/T, 4 per G/C) below 14 nt, otherwise
+/// `64.9 + 41 * (GC - 16.4) / N`.
+pub fn melting_temperature(sequence: &str) -> f64 {
+    let length = sequence.len();
+    let gc = sequence
+        .bytes()
+        .filter(|b| matches!(b.to_ascii_uppercase(), b'G' | b'C'))
+        .count() as f64;
+
+    if length < 14 {
+        2.0 * (length as f64 - gc) + 4.0 * gc
+    } else {
+        64.9 + 41.0 * (gc - 16.4) / length as f64
+    }
+}
+
+/// Reverse complement of a nucleotide sequence, IUPAC ambiguity codes included
 pub fn reverse_complement(sequence: &str) -> String {
-    // Get reverse complement and convert it back to String
-    revcomp(sequence.as_bytes())
-        .iter()
-        .map(|&b| b as char)
+    sequence
+        .bytes()
+        .rev()
+        .map(|b| complement_iupac(b) as char)
         .collect()
 }
 
-pub fn filter_hashmap(probes: ProbeSet, start: u64, end: u64, allow_outside: bool) -> ProbeSet {
+/// Keep probes lying within the GFF interval `start..=end` (1-based
+/// inclusive). Probe locations are 0-based: the first base of the window, or
+/// its 5' end (highest base) when `reverse`. A probe of length `k` at `loc`
+/// therefore covers `loc..loc + k` forward and `loc + 1 - k..=loc` reverse,
+/// and is inside when that covers no base before `start - 1` or after
+/// `end - 1`. `allow_outside` is the `--allow_outside` field, which is set
+/// (`SetFalse`) unless the flag is given: then every location must be
+/// inside, while with the flag one location inside is enough.
+pub fn filter_hashmap(
+    probes: ProbeSet,
+    start: u64,
+    end: u64,
+    reverse: bool,
+    allow_outside: bool,
+) -> ProbeSet {
+    let (first, last) = ((start as usize).saturating_sub(1), end as usize);
     probes
         .into_iter()
         .filter(|probe| {
+            let k = probe.kmer.len();
+            let inside = |&loc: &usize| {
+                if reverse {
+                    loc + 1 >= first + k && loc < last
+                } else {
+                    loc >= first && loc + k <= last
+                }
+            };
             if allow_outside {
-                // All locations must be within the range
-                probe
-                    .locations
-                    .iter()
-                    .all(|&pos| pos >= start as usize && pos <= end as usize)
+                probe.locations.iter().all(inside)
             } else {
-                // At least one location must be within the range
-                probe
-                    .locations
-                    .iter()
-                    .any(|&pos| pos >= start as usize && pos <= end as usize)
+                probe.locations.iter().any(inside)
             }
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::probes::Probes;
+
+    #[test]
+    fn ambiguity_codes_complement_to_their_partner_set() {
+        for (code, complement) in [
+            ("R", "Y"),
+            ("Y", "R"),
+            ("K", "M"),
+            ("M", "K"),
+            ("B", "V"),
+            ("V", "B"),
+            ("D", "H"),
+            ("H", "D"),
+        ] {
+            assert_eq!(reverse_complement(code), complement, "{}", code);
+        }
+    }
+
+    #[test]
+    fn self_complementary_codes_are_unchanged() {
+        assert_eq!(reverse_complement("S"), "S");
+        assert_eq!(reverse_complement("W"), "W");
+        assert_eq!(reverse_complement("N"), "N");
+    }
+
+    #[test]
+    fn reverse_complement_reverses_and_keeps_case() {
+        assert_eq!(reverse_complement("AACGTR"), "YACGTT");
+        assert_eq!(reverse_complement("acgRn"), "nYcgt");
+        assert_eq!(reverse_complement("U"), "A");
+    }
+
+    /// 5-mers at `locations` (one probe each), filtered to the 1-based
+    /// interval 11..=20, i.e. 0-based bases 10..=19.
+    fn kept_in_interval(locations: &[usize], reverse: bool) -> Vec<usize> {
+        let probes = locations
+            .iter()
+            .map(|&loc| Probes::new("ACGTA".to_string(), vec![loc]))
+            .collect();
+        filter_hashmap(probes, 11, 20, reverse, true)
+            .iter()
+            .map(|p| p.locations[0])
+            .collect()
+    }
+
+    #[test]
+    fn forward_probes_may_start_on_the_first_and_end_on_the_last_base() {
+        assert_eq!(kept_in_interval(&[9, 10, 15, 16], false), [10, 15]);
+    }
+
+    #[test]
+    fn reverse_probes_are_placed_by_their_highest_base() {
+        assert_eq!(kept_in_interval(&[13, 14, 19, 20], true), [14, 19]);
+    }
+
+    #[test]
+    fn allow_outside_needs_one_location_inside() {
+        let probe = || vec![Probes::new("ACGTA".to_string(), vec![10, 30])];
+        assert!(filter_hashmap(probe(), 11, 20, false, true).is_empty());
+        assert_eq!(filter_hashmap(probe(), 11, 20, false, false).len(), 1);
+    }
+}
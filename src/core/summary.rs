@@ -0,0 +1,271 @@
+use log::*;
+use std::io::Write;
+
+use crate::cli::ReportFormat;
+use crate::core::probes::json_string;
+use crate::error::Result;
+
+/// Counts collected through the probe pipeline and logged at the end of a run.
+#[derive(Debug, Default, Clone)]
+pub struct RunSummary {
+    pub targets_requested: usize,
+    pub targets_found: usize,
+    pub targets_with_candidates: usize,
+    pub candidate_probes: usize,
+    pub after_filters: usize,
+    pub after_selection: usize,
+    /// `None` when the off-target screen was skipped
+    pub after_off_target: Option<usize>,
+    /// Targets below `--min_probes_warn`; `None` when the check is off
+    pub low_yield_targets: Option<Vec<String>>,
+    /// Targets that produced no candidate probes, and why
+    pub skipped_targets: Vec<SkippedTarget>,
+    /// Targets shorter than the kmer size given one probe by `--allow_short`
+    pub short_targets: Vec<String>,
+}
+
+/// A target name and why it produced no candidate probes.
+pub type SkippedTarget = (String, SkipReason);
+
+/// Why a target produced no candidate probes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Not in the annotation
+    NotFound,
+    /// Its sequence is not in the reference
+    MissingSequence,
+    /// Its coordinates fall outside its reference sequence
+    OutsideReference,
+    /// Shorter than the kmer size (see `--allow_short`)
+    TooShort,
+    /// Long enough, but no window survived tiling (e.g. `--edge_trim`)
+    NoCandidates,
+}
+
+impl SkipReason {
+    /// Name used in TSV and JSON summaries.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::NotFound => "not_found",
+            SkipReason::MissingSequence => "missing_sequence",
+            SkipReason::OutsideReference => "outside_reference",
+            SkipReason::TooShort => "too_short",
+            SkipReason::NoCandidates => "no_candidates",
+        }
+    }
+}
+
+impl RunSummary {
+    /// Emit the summary in `format`: to `file` when given, otherwise `human`
+    /// goes to the log (and also stdout with `print_human`) and `tsv`/`json`
+    /// to stdout for pipelines.
+    pub fn report(
+        &self,
+        format: ReportFormat,
+        file: Option<&str>,
+        print_human: bool,
+    ) -> Result<()> {
+        let rendered = match format {
+            ReportFormat::Human => {
+                self.log();
+                if file.is_none() && !print_human {
+                    return Ok(());
+                }
+                self.to_human()
+            }
+            ReportFormat::Tsv => self.to_tsv(),
+            ReportFormat::Json => self.to_json(),
+        };
+        match file {
+            Some(path) => {
+                std::fs::write(path, rendered)?;
+                info!("Wrote the run summary to {}", path);
+            }
+            None => std::io::stdout().write_all(rendered.as_bytes())?,
+        }
+        Ok(())
+    }
+
+    /// Record that `target` produced no candidates, keeping the first reason
+    /// given for it.
+    pub fn skip(&mut self, target: &str, reason: SkipReason) {
+        if !self.skipped_targets.iter().any(|(name, _)| name == target) {
+            self.skipped_targets.push((target.to_string(), reason));
+        }
+    }
+
+    /// (name, value) of every counter, in pipeline order. Skipped stages
+    /// have no value.
+    fn counters(&self) -> Vec<(&'static str, Option<usize>)> {
+        vec![
+            ("targets_requested", Some(self.targets_requested)),
+            ("targets_found", Some(self.targets_found)),
+            (
+                "targets_with_candidates",
+                Some(self.targets_with_candidates),
+            ),
+            ("candidate_probes", Some(self.candidate_probes)),
+            ("after_filters", Some(self.after_filters)),
+            ("after_selection", Some(self.after_selection)),
+            ("after_off_target", self.after_off_target),
+            (
+                "low_yield_targets",
+                self.low_yield_targets.as_ref().map(Vec::len),
+            ),
+            ("skipped_targets", Some(self.skipped_targets.len())),
+            ("short_targets", Some(self.short_targets.len())),
+        ]
+    }
+
+    /// A header row and one value row; skipped stages are empty, and the
+    /// last columns list the low-yield targets, the skipped targets as
+    /// `name:reason` and the short targets, comma-separated.
+    fn to_tsv(&self) -> String {
+        let counters = self.counters();
+        let mut header: Vec<&str> = counters.iter().map(|(name, _)| *name).collect();
+        let mut values: Vec<String> = counters
+            .iter()
+            .map(|(_, value)| value.map_or(String::new(), |v| v.to_string()))
+            .collect();
+        header.push("low_yield_target_names");
+        values.push(
+            self.low_yield_targets
+                .as_ref()
+                .map_or(String::new(), |names| names.join(",")),
+        );
+        header.push("skipped_target_reasons");
+        values.push(
+            self.skipped_targets
+                .iter()
+                .map(|(name, reason)| format!("{}:{}", name, reason.as_str()))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        header.push("short_target_names");
+        values.push(self.short_targets.join(","));
+        format!("{}\n{}\n", header.join("\t"), values.join("\t"))
+    }
+
+    /// One JSON object; skipped stages are `null`.
+    fn to_json(&self) -> String {
+        let mut fields: Vec<String> = self
+            .counters()
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}:{}",
+                    json_string(name),
+                    value.map_or("null".to_string(), |v| v.to_string())
+                )
+            })
+            .collect();
+        let names = self
+            .low_yield_targets
+            .as_ref()
+            .map_or("null".to_string(), |names| {
+                format!(
+                    "[{}]",
+                    names
+                        .iter()
+                        .map(|n| json_string(n))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )
+            });
+        fields.push(format!(
+            "{}:{}",
+            json_string("low_yield_target_names"),
+            names
+        ));
+        let reasons: Vec<String> = self
+            .skipped_targets
+            .iter()
+            .map(|(name, reason)| format!("{}:{}", json_string(name), json_string(reason.as_str())))
+            .collect();
+        fields.push(format!(
+            "{}:{{{}}}",
+            json_string("skipped_target_reasons"),
+            reasons.join(",")
+        ));
+        let short: Vec<String> = self.short_targets.iter().map(|n| json_string(n)).collect();
+        fields.push(format!(
+            "{}:[{}]",
+            json_string("short_target_names"),
+            short.join(",")
+        ));
+        format!("{{{}}}\n", fields.join(","))
+    }
+
+    fn to_human(&self) -> String {
+        self.human_lines().join("\n") + "\n"
+    }
+
+    pub fn log(&self) {
+        for line in self.human_lines() {
+            info!("{}", line);
+        }
+    }
+
+    fn human_lines(&self) -> Vec<String> {
+        let off_target = match self.after_off_target {
+            Some(n) => n.to_string(),
+            None => "skipped".to_string(),
+        };
+
+        let mut lines = vec!["Run summary:".to_string()];
+        lines.push(format!(
+            "  Targets requested:           {}",
+            self.targets_requested
+        ));
+        lines.push(format!(
+            "  Targets found:               {}",
+            self.targets_found
+        ));
+        lines.push(format!(
+            "  Targets with candidates:     {}",
+            self.targets_with_candidates
+        ));
+        lines.push(format!(
+            "  Candidate probes:            {}",
+            self.candidate_probes
+        ));
+        lines.push(format!(
+            "  After GC/complexity filters: {}",
+            self.after_filters
+        ));
+        lines.push(format!(
+            "  After best-probe selection:  {}",
+            self.after_selection
+        ));
+        lines.push(format!("  After off-target screen:     {}", off_target));
+        if let Some(low_yield) = &self.low_yield_targets {
+            lines.push(format!(
+                "  Targets below probe minimum: {}",
+                low_yield.len()
+            ));
+            if !low_yield.is_empty() {
+                lines.push(format!("    {}", low_yield.join(", ")));
+            }
+        }
+        if !self.skipped_targets.is_empty() {
+            lines.push(format!(
+                "  Targets without candidates:  {}",
+                self.skipped_targets.len()
+            ));
+            let reasons: Vec<String> = self
+                .skipped_targets
+                .iter()
+                .map(|(name, reason)| format!("{} ({})", name, reason.as_str()))
+                .collect();
+            lines.push(format!("    {}", reasons.join(", ")));
+        }
+        if !self.short_targets.is_empty() {
+            lines.push(format!(
+                "  Short targets, one probe:    {}",
+                self.short_targets.len()
+            ));
+            lines.push(format!("    {}", self.short_targets.join(", ")));
+        }
+        lines
+    }
+}
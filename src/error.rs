@@ -5,9 +5,18 @@ pub enum VisiogenError {
     #[error("Failed to parse GFF3 file: {0}")]
     GffParseError(String),
 
+    #[error("Failed to parse FASTA file: {0}")]
+    FastaParseError(String),
+
     #[error("Failed to parse GFA file: {0}")]
     GfaParseError(String),
 
+    #[error("Failed to parse BED file: {0}")]
+    BedParseError(String),
+
+    #[error("Failed to parse variant file: {0}")]
+    VariantParseError(String),
+
     #[error("Failed to build off-target indexes: {0}")]
     IndexBuildError(String),
 
@@ -17,12 +26,18 @@ pub enum VisiogenError {
     #[error("Missing required argument: {0}")]
     MissingArgument(String),
 
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
     #[error("Gene processing error: {0}")]
     GeneProcessingError(String),
 
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
 
+    #[error("No probes found: {0}")]
+    NoProbes(String),
+
     #[error("no unique k‑mers found in '{gene}' ({start}:{end})")]
     NoUniqueKmers { gene: String, start: u64, end: u64 },
 
@@ -30,4 +45,25 @@ pub enum VisiogenError {
     Other(String),
 }
 
+impl VisiogenError {
+    /// Process exit code for this failure class, so wrapper scripts can tell
+    /// them apart: 2 for bad input or arguments, 3 for index build/query
+    /// failures, 4 when no probes survived, 1 for anything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VisiogenError::GffParseError(_)
+            | VisiogenError::FastaParseError(_)
+            | VisiogenError::GfaParseError(_)
+            | VisiogenError::BedParseError(_)
+            | VisiogenError::VariantParseError(_)
+            | VisiogenError::MissingArgument(_)
+            | VisiogenError::InvalidArgument(_)
+            | VisiogenError::IoError(_) => 2,
+            VisiogenError::IndexBuildError(_) | VisiogenError::IndexQueryError(_) => 3,
+            VisiogenError::NoProbes(_) | VisiogenError::NoUniqueKmers { .. } => 4,
+            VisiogenError::GeneProcessingError(_) | VisiogenError::Other(_) => 1,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, VisiogenError>;
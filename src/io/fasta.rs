@@ -0,0 +1,101 @@
+use log::warn;
+use needletail::{parse_fastx_file, parse_fastx_reader, FastxReader};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+
+use crate::cli::FastaCase;
+use crate::error::{Result, VisiogenError};
+
+/// Read every record of a FASTA into a map of record ID (header up to the
+/// first whitespace) to sequence.
+pub fn read_fasta(fasta_path: &str) -> Result<HashMap<String, String>> {
+    Ok(read_fasta_records(fasta_path)?.into_iter().collect())
+}
+
+/// Read every record of a FASTA as (record ID, sequence) pairs in file order.
+pub fn read_fasta_records(fasta_path: &str) -> Result<Vec<(String, String)>> {
+    let reader = parse_fastx_file(fasta_path).map_err(|e| {
+        VisiogenError::FastaParseError(format!("Failed to open {}: {}", fasta_path, e))
+    })?;
+    collect_records(reader, fasta_path, false)
+}
+
+/// Read every record of a FASTA as (whole header line, sequence) pairs in
+/// file order, keeping the description after the ID.
+pub fn read_fasta_with_headers(fasta_path: &str) -> Result<Vec<(String, String)>> {
+    let reader = parse_fastx_file(fasta_path).map_err(|e| {
+        VisiogenError::FastaParseError(format!("Failed to open {}: {}", fasta_path, e))
+    })?;
+    collect_records(reader, fasta_path, true)
+}
+
+/// Parse FASTA text held in memory (e.g. the `##FASTA` section of a GFF3)
+/// into a map of record ID to sequence. `source` names it in errors.
+pub fn parse_fasta_text(text: String, source: &str) -> Result<HashMap<String, String>> {
+    let reader = parse_fastx_reader(Cursor::new(text.into_bytes())).map_err(|e| {
+        VisiogenError::FastaParseError(format!("Failed to read FASTA in {}: {}", source, e))
+    })?;
+    Ok(collect_records(reader, source, false)?
+        .into_iter()
+        .collect())
+}
+
+fn collect_records(
+    mut reader: Box<dyn FastxReader + '_>,
+    source: &str,
+    whole_header: bool,
+) -> Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    while let Some(record) = reader.next() {
+        let seqrec = record.map_err(|e| {
+            VisiogenError::FastaParseError(format!("Malformed record in {}: {}", source, e))
+        })?;
+        let header = String::from_utf8_lossy(seqrec.id());
+        let id = if whole_header {
+            header.into_owned()
+        } else {
+            header
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        };
+        let sequence = String::from_utf8_lossy(&seqrec.seq()).into_owned();
+        records.push((id, sequence));
+    }
+
+    Ok(records)
+}
+
+/// Put every sequence in `case` (`--fasta_case`) before tiling.
+pub fn set_case<'a>(sequences: impl Iterator<Item = &'a mut String>, case: FastaCase) {
+    if case == FastaCase::Preserve {
+        return;
+    }
+    for seq in sequences {
+        case.apply(seq);
+    }
+}
+
+/// Make record IDs unique so each record can stand as its own target:
+/// repeats of an ID get a `.2`, `.3`, ... suffix (skipping any suffix that is
+/// already taken), with a warning.
+pub fn dedup_record_ids(records: &mut [(String, String)]) {
+    let mut seen: HashSet<String> = records.iter().map(|(id, _)| id.clone()).collect();
+    let mut first = HashSet::new();
+    for (id, _) in records.iter_mut() {
+        if first.insert(id.clone()) {
+            continue;
+        }
+        let renamed = (2..)
+            .map(|n| format!("{}.{}", id, n))
+            .find(|candidate| !seen.contains(candidate))
+            .unwrap();
+        warn!(
+            "Duplicate FASTA record ID {}; renaming it to {}",
+            id, renamed
+        );
+        seen.insert(renamed.clone());
+        *id = renamed;
+    }
+}
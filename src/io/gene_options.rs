@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::cli::KmerOptions;
+use crate::error::{Result, VisiogenError};
+
+/// The k-mer options each target is designed with: the global ones, replaced
+/// key by key for the genes listed in a `--gene_options` file.
+#[derive(Clone)]
+pub struct GeneOptions {
+    global: KmerOptions,
+    per_gene: HashMap<String, KmerOptions>,
+}
+
+impl GeneOptions {
+    /// No overrides: every target uses `global`.
+    pub fn new(global: &KmerOptions) -> Self {
+        GeneOptions {
+            global: global.clone(),
+            per_gene: HashMap::new(),
+        }
+    }
+
+    /// Options of targets without overrides.
+    pub fn global(&self) -> &KmerOptions {
+        &self.global
+    }
+
+    pub fn for_gene(&self, gene: &str) -> &KmerOptions {
+        self.per_gene.get(gene).unwrap_or(&self.global)
+    }
+
+    /// Genes with their own options, and those options.
+    pub fn overrides(&self) -> impl Iterator<Item = (&String, &KmerOptions)> {
+        self.per_gene.iter()
+    }
+}
+
+/// Read a `--gene_options` file: tab-separated lines of a gene name followed
+/// by `key=value` overrides, keys named like the flags (e.g. `min_gc=30`).
+/// Blank lines and `#` comments are ignored; a gene listed twice takes the
+/// overrides of both lines.
+pub fn read_gene_options(path: &str, global: &KmerOptions) -> Result<GeneOptions> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        VisiogenError::InvalidArgument(format!("Failed to read --gene_options {}: {}", path, e))
+    })?;
+
+    let mut options = GeneOptions::new(global);
+    for (line_no, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t').map(str::trim);
+        let gene = fields.next().unwrap_or_default();
+        let invalid = |what: String| {
+            VisiogenError::InvalidArgument(format!(
+                "--gene_options {} line {}: {}",
+                path,
+                line_no + 1,
+                what
+            ))
+        };
+        if gene.is_empty() {
+            return Err(invalid("missing gene name".to_string()));
+        }
+
+        let gene_options = options
+            .per_gene
+            .entry(gene.to_string())
+            .or_insert_with(|| global.clone());
+        for field in fields.filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected key=value, got '{}'", field)))?;
+            apply_override(gene_options, key.trim(), value.trim()).map_err(invalid)?;
+        }
+    }
+    Ok(options)
+}
+
+/// Set the option named `key` (as its flag, without dashes) to `value`.
+fn apply_override(
+    options: &mut KmerOptions,
+    key: &str,
+    value: &str,
+) -> std::result::Result<(), String> {
+    match key {
+        "kmer_size" => options.kmer_size = parse(key, value)?,
+        "center_base" => options.center_base = Some(parse(key, value)?),
+        "min_gc" => options.min_gc = parse(key, value)?,
+        "max_gc" => options.max_gc = parse(key, value)?,
+        "skip_gc" => options.skip_gc = parse(key, value)?,
+        "gc_exclude_junction" => options.gc_exclude_junction = parse(key, value)?,
+        "max_homopolymer" => options.max_homopolymer = parse(key, value)?,
+        "max_homopolymer_run" => options.max_homopolymer_run = Some(parse(key, value)?),
+        "unique_only" => options.unique_only = parse(key, value)?,
+        "max_gc_skew" => options.max_gc_skew = Some(parse(key, value)?),
+        "skip_masked" => options.skip_masked = parse(key, value)?,
+        "min_junction_gc" => options.min_junction_gc = Some(parse(key, value)?),
+        "edge_trim" => options.edge_trim = parse(key, value)?,
+        "allow_short" => options.allow_short = parse(key, value)?,
+        "min_tm" => options.min_tm = parse(key, value)?,
+        _ => return Err(format!("unknown option '{}'", key)),
+    }
+    Ok(())
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> std::result::Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for {}", value, key))
+}
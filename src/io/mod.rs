@@ -1,2 +1,5 @@
+pub mod fasta;
+pub mod gene_options;
 pub mod output;
 pub mod utils;
+pub mod variants;
@@ -1,37 +1,510 @@
-use crate::cli::Args;
+use crate::cli::{Args, OutputFormat};
 use crate::core::probes::GeneKmers;
+use crate::core::seq::melting_temperature;
+use crate::core::summary::RunSummary;
 use crate::error::{Result, VisiogenError};
-use crate::processing::index::query_kmers_across_indexes;
+use crate::logging;
+#[cfg(feature = "index")]
+use crate::processing::index::{apply_hit_limit, query_kmers_across_indexes, FileOptions};
 use chrono::Local;
-use log::info;
-use std::path::Path;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 
+/// Screen `all_kmers` against any off-target indexes and write the survivors,
+/// returning what was written.
 pub fn write_filtered_kmers(
     all_kmers: Vec<GeneKmers>,
     args: &Args,
     filename_prefix: &str,
-) -> Result<()> {
-    let kmers_to_write = match &args.off_target_directory {
-        Some(off_target_dir) => query_kmers_across_indexes(
-            Path::new(off_target_dir),
-            all_kmers.clone(),
-            args.threads,
-            args.max_hits,
-            args.recursive,
-        )
-        .map_err(|e| VisiogenError::IndexQueryError(e.to_string()))?,
-        None => {
-            info!("Skipping off-target check as no off-target directory was provided.");
-            all_kmers.clone()
+    summary: &mut RunSummary,
+) -> Result<Vec<GeneKmers>> {
+    let off_target = &args.off_target;
+    let (kmers_to_write, extra_thresholds) = if !off_target.screens() {
+        if off_target.no_offtarget {
+            info!("Skipping off-target check as requested by --no_offtarget.");
+        } else {
+            info!("Skipping off-target check as no off-target directory or FASTA was provided.");
+        }
+        (all_kmers.clone(), Vec::new())
+    } else {
+        logging::set_stage("off_target");
+        let mut screened = screen_off_targets(all_kmers.clone(), args)?;
+        if screened.len() > 1 {
+            for (max_hits, set) in &screened {
+                info!(
+                    "max_hits {}: {} probe(s) survive",
+                    max_hits,
+                    set.iter().map(|gk| gk.kmers.len()).sum::<usize>()
+                );
+            }
         }
+        let (_, primary) = screened.remove(0);
+        summary.after_off_target = Some(primary.iter().map(|gk| gk.kmers.len()).sum());
+        (primary, screened)
     };
 
+    if args.min_probes_warn > 0 {
+        summary.low_yield_targets = Some(low_yield_targets(
+            &all_kmers,
+            &kmers_to_write,
+            args.min_probes_warn,
+        ));
+    }
+
+    logging::set_stage("output");
+    if args.summary_only {
+        info!(
+            "Not writing probe files (--summary_only): {} probe(s) would be written",
+            kmers_to_write
+                .iter()
+                .map(|gk| gk.kmers.len())
+                .sum::<usize>()
+        );
+        return Ok(kmers_to_write);
+    }
+
     let timestamp = Local::now().format("%d-%m-%H-%M").to_string();
-    let filename = format!("{}_{}.fasta", filename_prefix, timestamp);
+    let filename = format!(
+        "{}_{}.{}",
+        filename_prefix,
+        timestamp,
+        args.format.extension()
+    );
+
+    write_probe_file(&kmers_to_write, args, &filename)?;
+
+    // Further --max_hits thresholds each get their own file
+    for (max_hits, set) in &extra_thresholds {
+        let extra_file = format!(
+            "{}_max_hits_{}_{}.{}",
+            filename_prefix,
+            max_hits,
+            timestamp,
+            args.format.extension()
+        );
+        write_probe_file(set, args, &extra_file)?;
+        info!(
+            "Wrote the max_hits {} probe set to {}",
+            max_hits, extra_file
+        );
+    }
+
+    if let Some(panel_file) = &args.final_panel {
+        write_final_panel(&kmers_to_write, args, panel_file)?;
+    }
+
+    if args.report_coverage_gaps {
+        let gaps_file = format!("{}_gaps_{}.bed", filename_prefix, timestamp);
+        write_coverage_gaps(&all_kmers, &kmers_to_write, &gaps_file)?;
+    }
+
+    kmers_to_write.iter().for_each(GeneKmers::log_kmers);
+
+    Ok(kmers_to_write)
+}
+
+/// Write every probe of every target with the metrics the filters use (and
+/// Tm) as TSV: all candidates before any filter for `--dump_kmers`, or the
+/// records of an `annotate`d FASTA. Locations are 0-based, comma-separated.
+pub fn dump_kmers(gene_kmers: &[GeneKmers], filename: &str) -> Result<()> {
+    let mut out = std::io::BufWriter::new(File::create(filename)?);
+    writeln!(
+        out,
+        "gene\tkmer\tlocations\tfirst_half_gc\tsecond_half_gc\tcomplexity\tpoly_at_run\tmax_homopolymer_run\tjunction_base\tjunction_gc\tjunction_complexity\ttm\tscore"
+    )?;
+    let mut total = 0;
+    for gk in gene_kmers {
+        for probe in &gk.kmers {
+            let locations = probe
+                .locations
+                .iter()
+                .map(|loc| loc.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{:.4}\t{}\t{}\t{}\t{}\t{:.4}\t{:.1}\t{:.4}",
+                gk.gene,
+                probe.kmer,
+                locations,
+                probe.first_half_gc,
+                probe.second_half_gc,
+                probe.complexity,
+                probe.poly_at_run,
+                probe.max_homopolymer_run,
+                probe.junction_base,
+                probe.junction_gc,
+                probe.junction_complexity,
+                melting_temperature(&probe.kmer),
+                probe.score
+            )?;
+        }
+        total += gk.kmers.len();
+    }
+    out.flush()?;
+    info!("Wrote metrics of {} k-mer(s) to {}", total, filename);
+    Ok(())
+}
+
+/// Write the `--hit_matrix` TSV: one row per screened probe (gene and
+/// k-mer), one column per off-target index searched, 1 where the index
+/// contains the probe and 0 otherwise. With no index searched, nothing was
+/// screened and only the `gene` and `kmer` header is written.
+#[cfg(feature = "index")]
+pub fn write_hit_matrix(
+    filename: &str,
+    indexes: &[String],
+    gene_kmers: &[GeneKmers],
+) -> Result<()> {
+    let mut out = std::io::BufWriter::new(File::create(filename)?);
+    let header: Vec<&str> = ["gene", "kmer"]
+        .into_iter()
+        .chain(indexes.iter().map(String::as_str))
+        .collect();
+    writeln!(out, "{}", header.join("\t"))?;
+    let mut rows = 0;
+    for gk in gene_kmers {
+        for probe in &gk.kmers {
+            let hits: HashSet<&str> = gk
+                .kmer_hits
+                .get(&probe.kmer)
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect();
+            let cells: Vec<&str> = [gk.gene.as_str(), probe.kmer.as_str()]
+                .into_iter()
+                .chain(indexes.iter().map(|index| {
+                    if hits.contains(index.as_str()) {
+                        "1"
+                    } else {
+                        "0"
+                    }
+                }))
+                .collect();
+            writeln!(out, "{}", cells.join("\t"))?;
+            rows += 1;
+        }
+    }
+    out.flush()?;
+    info!(
+        "Wrote a {} probe x {} index hit matrix to {}",
+        rows,
+        indexes.len(),
+        filename
+    );
+    Ok(())
+}
+
+/// Write one BED line per probe location (`target start end name score
+/// strand`), the score scaled to 0-1000. Locations are 0-based offsets on
+/// the sequence named in the first column; the strand is unknown (`.`).
+pub fn write_probe_bed(gene_kmers: &[GeneKmers], filename: &str) -> Result<()> {
+    let mut out = std::io::BufWriter::new(File::create(filename)?);
+    let mut total = 0;
+    for gk in gene_kmers {
+        for (i, probe) in gk.kmers.iter().enumerate() {
+            for &loc in &probe.locations {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t.",
+                    gk.seqid,
+                    loc,
+                    loc + probe.kmer.len(),
+                    gk.probe_id(i),
+                    (probe.score.clamp(0.0, 1.0) * 1000.0).round() as u32
+                )?;
+                total += 1;
+            }
+        }
+    }
+    out.flush()?;
+    info!("Wrote {} probe interval(s) to {}", total, filename);
+    Ok(())
+}
+
+/// Create `filename` with the header for the output format and write the
+/// probes, grouped by gene or in global score order.
+fn write_probe_file(kmers: &[GeneKmers], args: &Args, filename: &str) -> Result<()> {
+    match args.format {
+        OutputFormat::Gff3 => writeln!(File::create(filename)?, "##gff-version 3")?,
+        OutputFormat::Csv if args.flank > 0 => writeln!(
+            File::create(filename)?,
+            "gene,sequence,specificity,flanked_sequence"
+        )?,
+        OutputFormat::Csv => writeln!(File::create(filename)?, "gene,sequence,specificity")?,
+        OutputFormat::Fasta | OutputFormat::Jsonl => (),
+    }
+
+    if args.collapse_duplicates {
+        return write_collapsed(kmers, args, filename);
+    }
+    if args.global_ranking {
+        return write_ranked(kmers, args, filename);
+    }
+    let filename = filename.to_string();
+    kmers.iter().for_each(|gk| match args.format {
+        OutputFormat::Fasta => {
+            gk.write_all_keys_to_file(filename.clone(), args.output_revcomp, args.wrap)
+        }
+        OutputFormat::Gff3 => gk.write_gff3_to_file(filename.clone()),
+        OutputFormat::Csv => gk.write_csv_to_file(filename.clone()),
+        OutputFormat::Jsonl => gk.write_jsonl_to_file(filename.clone()),
+    });
+    Ok(())
+}
+
+/// Write each distinct probe sequence once, as FASTA, for `--collapse_duplicates`.
+/// The record keeps the name and header of its first use, in gene order (or
+/// global score order with `--global_ranking`), and adds
+/// ` serves=<name>:<loc>,<loc>;<name>:<loc>...` listing every gene's probe
+/// with that sequence.
+fn write_collapsed(kmers: &[GeneKmers], args: &Args, filename: &str) -> Result<()> {
+    let order: Vec<(&GeneKmers, usize)> = if args.global_ranking {
+        global_ranking(kmers)
+    } else {
+        kmers
+            .iter()
+            .flat_map(|gk| (0..gk.kmers.len()).map(move |i| (gk, i)))
+            .collect()
+    };
+
+    let mut uses: HashMap<&str, Vec<(&GeneKmers, usize)>> = HashMap::new();
+    let mut first_uses = Vec::new();
+    for (gk, i) in order {
+        let users = uses.entry(gk.kmers[i].kmer.as_str()).or_default();
+        if users.is_empty() {
+            first_uses.push((gk, i));
+        }
+        users.push((gk, i));
+    }
 
-    kmers_to_write
+    let mut out = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(filename)?;
+    for &(gk, i) in &first_uses {
+        let serves = uses[gk.kmers[i].kmer.as_str()]
+            .iter()
+            .map(|(user, j)| {
+                let locations: Vec<String> = user.kmers[*j]
+                    .locations
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+                format!("{}:{}", user.probe_id(*j), locations.join(","))
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+        writeln!(
+            out,
+            ">{} serves={}",
+            gk.fasta_header(i, args.output_revcomp),
+            serves
+        )?;
+        gk.write_fasta_sequence(&mut out, i, args.output_revcomp, args.wrap);
+    }
+
+    let total: usize = kmers.iter().map(|gk| gk.kmers.len()).sum();
+    info!(
+        "Collapsed {} probe(s) to {} distinct sequence(s) in {}",
+        total,
+        first_uses.len(),
+        filename
+    );
+    Ok(())
+}
+
+/// Write the hand-off panel: the written probes with no off-target hit that
+/// counts against them (hits spanning fewer than `--min_offtarget_genomes`
+/// distinct indexes are tolerated), as FASTA. Records keep the names they
+/// have in the main output.
+fn write_final_panel(kmers: &[GeneKmers], args: &Args, filename: &str) -> Result<()> {
+    let min_genomes = args.off_target.min_offtarget_genomes;
+    let mut out = File::create(filename)?;
+    let mut written = 0;
+
+    for gk in kmers {
+        for (i, probe) in gk.kmers.iter().enumerate() {
+            let counted = gk
+                .kmer_hits
+                .get(&probe.kmer)
+                .is_some_and(|files| files.iter().collect::<HashSet<_>>().len() >= min_genomes);
+            if !counted {
+                gk.write_fasta_record(&mut out, i, args.output_revcomp, args.wrap);
+                written += 1;
+            }
+        }
+    }
+
+    info!("Wrote {} probe(s) to the final panel {}", written, filename);
+    if written == 0 {
+        warn!("The final panel {} is empty", filename);
+    }
+    Ok(())
+}
+
+/// Write, as BED (`seqid start end gene . strand`), every stretch of each
+/// selected target that no written probe covers. Targets the off-target
+/// screen emptied are reported as one gap spanning the whole target.
+fn write_coverage_gaps(
+    selected: &[GeneKmers],
+    written: &[GeneKmers],
+    filename: &str,
+) -> Result<()> {
+    let written_by_gene: HashMap<&str, &GeneKmers> =
+        written.iter().map(|gk| (gk.gene.as_str(), gk)).collect();
+    let mut out = File::create(filename)?;
+    let mut total = 0;
+
+    for gk in selected {
+        let gaps = match written_by_gene.get(gk.gene.as_str()) {
+            Some(target) => target.coverage_gaps(),
+            None => vec![((gk.start as usize).saturating_sub(1), gk.end as usize)],
+        };
+        for (start, end) in &gaps {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t.\t{}",
+                gk.seqid, start, end, gk.gene, gk.strand
+            )?;
+        }
+        total += gaps.len();
+    }
+
+    info!("Wrote {} coverage gap(s) to {}", total, filename);
+    Ok(())
+}
+
+/// Every probe across all genes as (gene, probe index) pairs, best score
+/// first. Ties are broken by gene name, then sequence, so the order is stable
+/// between runs.
+fn global_ranking(gene_kmers: &[GeneKmers]) -> Vec<(&GeneKmers, usize)> {
+    let mut ranked: Vec<(&GeneKmers, usize)> = gene_kmers
         .iter()
-        .for_each(|gk| gk.log_and_write_kmers(args.kmer_options.kmer_size, filename.clone()));
+        .flat_map(|gk| (0..gk.kmers.len()).map(move |i| (gk, i)))
+        .collect();
+    ranked.sort_by(|(a, i), (b, j)| {
+        let (pa, pb) = (&a.kmers[*i], &b.kmers[*j]);
+        pb.score
+            .total_cmp(&pa.score)
+            .then_with(|| a.gene.cmp(&b.gene))
+            .then_with(|| pa.kmer.cmp(&pb.kmer))
+    });
+    ranked
+}
+
+/// Write all probes as one list in global score order. Probes keep their
+/// stable names (`probe_id`), so each record still identifies its gene.
+fn write_ranked(gene_kmers: &[GeneKmers], args: &Args, filename: &str) -> Result<()> {
+    let mut out = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(filename)?;
 
+    if args.format == OutputFormat::Gff3 {
+        for gk in gene_kmers {
+            gk.write_gff3_gene_record(&mut out);
+        }
+    }
+    for (gk, i) in global_ranking(gene_kmers) {
+        match args.format {
+            OutputFormat::Fasta => {
+                gk.write_fasta_record(&mut out, i, args.output_revcomp, args.wrap)
+            }
+            OutputFormat::Gff3 => gk.write_gff3_probe_records(&mut out, i),
+            OutputFormat::Csv => gk.write_csv_row(&mut out, i),
+            OutputFormat::Jsonl => gk.write_jsonl_record(&mut out, i),
+        }
+    }
+    info!(
+        "Wrote probes from {} gene(s) in global score order",
+        gene_kmers.len()
+    );
     Ok(())
 }
+
+/// Warn about every target whose final probe count is below `min_probes`,
+/// including targets the off-target screen removed entirely.
+fn low_yield_targets(
+    selected: &[GeneKmers],
+    written: &[GeneKmers],
+    min_probes: usize,
+) -> Vec<String> {
+    let written_counts: HashMap<&str, usize> = written
+        .iter()
+        .map(|gk| (gk.gene.as_str(), gk.kmers.len()))
+        .collect();
+
+    selected
+        .iter()
+        .filter_map(|gk| {
+            let count = written_counts.get(gk.gene.as_str()).copied().unwrap_or(0);
+            if count >= min_probes {
+                return None;
+            }
+            warn!(
+                gene = gk.gene.as_str();
+                "Target {} has only {} usable probe(s) (below --min_probes_warn {}); consider relaxing filters",
+                gk.gene, count, min_probes
+            );
+            Some(gk.gene.clone())
+        })
+        .collect()
+}
+
+/// Query the off-targets once and apply each `--max_hits` threshold, in the
+/// order given, to the same hits.
+#[cfg(feature = "index")]
+fn screen_off_targets(kmers: Vec<GeneKmers>, args: &Args) -> Result<Vec<(usize, Vec<GeneKmers>)>> {
+    let off_target = &args.off_target;
+    let mut dirs: Vec<std::path::PathBuf> = off_target
+        .off_target_directory
+        .iter()
+        .map(Into::into)
+        .collect();
+    if let Some(list) = &off_target.index_list {
+        dirs.extend(crate::io::utils::read_index_list(list).map_err(|e| {
+            VisiogenError::IndexQueryError(format!("Failed to read --index_list {}: {}", list, e))
+        })?);
+    }
+    let fastas: Vec<std::path::PathBuf> =
+        off_target.off_target_fasta.iter().map(Into::into).collect();
+    let annotated = query_kmers_across_indexes(
+        &dirs,
+        &fastas,
+        kmers,
+        args.query_threads.unwrap_or(args.threads),
+        args.recursive,
+        off_target,
+        FileOptions {
+            io_retries: args.io_retries,
+            fasta_case: args.fasta_case,
+        },
+    )
+    .map_err(|e| VisiogenError::IndexQueryError(e.to_string()))?;
+
+    Ok(off_target
+        .max_hits
+        .iter()
+        .map(|&max_hits| {
+            (
+                max_hits,
+                apply_hit_limit(annotated.clone(), off_target, max_hits),
+            )
+        })
+        .collect())
+}
+
+#[cfg(not(feature = "index"))]
+fn screen_off_targets(
+    _kmers: Vec<GeneKmers>,
+    _args: &Args,
+) -> Result<Vec<(usize, Vec<GeneKmers>)>> {
+    Err(VisiogenError::IndexQueryError(
+        "off-target screening requires visiogen to be compiled with the `index` feature (nightly Rust)"
+            .to_string(),
+    ))
+}
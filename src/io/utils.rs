@@ -1,8 +1,10 @@
 use log::*;
-use rayon::ThreadPoolBuilder;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Once;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
 /// Find all files with the given extensions (e.g., ["fa", "fasta"]) in a directory.
@@ -13,49 +15,306 @@ pub fn find_files_with_extensions(
 ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
     let ext_set: std::collections::HashSet<_> =
         extensions.iter().map(|e| e.to_lowercase()).collect();
+    let matches = |p: &Path| {
+        p.is_file()
+            && p.extension()
+                .and_then(|s| s.to_str())
+                .map(|ext| ext_set.contains(&ext.to_lowercase()))
+                .unwrap_or(false)
+    };
+
+    // A missing or unreadable top-level directory is an error in both modes;
+    // unreadable entries below it are skipped with a warning
+    let entries = std::fs::read_dir(directory)
+        .map_err(|e| format!("Cannot read directory {}: {}", directory.display(), e))?;
 
     let files = if recursive {
         WalkDir::new(directory)
             .into_iter()
-            .filter_map(Result::ok)
-            .filter(|e| e.path().is_file())
-            .filter(|e| {
-                e.path()
-                    .extension()
-                    .and_then(|s| s.to_str())
-                    .map(|ext| ext_set.contains(&ext.to_lowercase()))
-                    .unwrap_or(false)
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!(
+                        "Skipping unreadable entry under {}: {}",
+                        directory.display(),
+                        e
+                    );
+                    None
+                }
             })
+            .filter(|e| matches(e.path()))
             .map(|e| e.into_path())
             .collect()
     } else {
-        std::fs::read_dir(directory)?
-            .filter_map(Result::ok)
-            .map(|e| e.path())
-            .filter(|p| {
-                p.is_file()
-                    && p.extension()
-                        .and_then(|s| s.to_str())
-                        .map(|ext| ext_set.contains(&ext.to_lowercase()))
-                        .unwrap_or(false)
+        entries
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry.path()),
+                Err(e) => {
+                    warn!(
+                        "Skipping unreadable entry in {}: {}",
+                        directory.display(),
+                        e
+                    );
+                    None
+                }
             })
+            .filter(|p| matches(p))
             .collect()
     };
 
     Ok(files)
 }
 
-pub fn configure_thread_pool(build_threads: usize) {
-    static INIT: Once = Once::new();
-    INIT.call_once(|| {
-        if build_threads > 0 {
-            ThreadPoolBuilder::new()
-                .num_threads(build_threads)
-                .build_global()
-                .expect("Failed to build global Rayon thread pool");
+/// Input path that stands for standard input, so BED intervals or a GFA can
+/// be piped in from other tools.
+pub const STDIN_PATH: &str = "-";
+
+/// Open `path` for reading line by line, or standard input when it is `-`.
+pub fn open_input(path: &str) -> std::io::Result<Box<dyn BufRead>> {
+    if path == STDIN_PATH {
+        Ok(Box::new(std::io::stdin().lock()))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// Name of an input path for messages.
+pub fn input_name(path: &str) -> &str {
+    if path == STDIN_PATH {
+        "<stdin>"
+    } else {
+        path
+    }
+}
+
+/// Find matching files across several directories, where a path that is
+/// itself a matching file is taken as is. Files reached through more than one
+/// directory (e.g. overlapping recursive paths) are returned once.
+pub fn find_files_in_directories(
+    directories: &[PathBuf],
+    extensions: &[&str],
+    recursive: bool,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut seen = std::collections::HashSet::new();
+    let mut files = Vec::new();
+
+    for directory in directories {
+        let found = if directory.is_file() && has_extension(directory, extensions) {
+            vec![directory.clone()]
         } else {
-            info!("Using default number of threads (all logical CPUs)");
-            num_cpus::get();
+            find_files_with_extensions(directory, extensions, recursive)?
+        };
+        for file in found {
+            let key = std::fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+            if seen.insert(key) {
+                files.push(file);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+}
+
+/// Read a list of index directories and `.cbl` files, one per line (blank
+/// lines and `#` comments ignored). Paths that do not exist are skipped with
+/// a warning.
+#[cfg(feature = "index")]
+pub fn read_index_list(list_path: &str) -> std::io::Result<Vec<PathBuf>> {
+    let text = std::fs::read_to_string(list_path)?;
+    let mut paths = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if path.exists() {
+            paths.push(path);
+        } else {
+            warn!(
+                "Skipping {} from {}: no such file or directory",
+                line, list_path
+            );
+        }
+    }
+    info!("Read {} index path(s) from {}", paths.len(), list_path);
+    Ok(paths)
+}
+
+/// A dedicated rayon pool for one phase, with `threads` threads (0 = one per
+/// logical CPU). Work run through `pool.install` uses this count whatever
+/// the global pool was built with.
+pub fn thread_pool(threads: usize) -> Result<ThreadPool, ThreadPoolBuildError> {
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+    debug!(
+        "Built a local pool of {} thread(s)",
+        pool.current_num_threads()
+    );
+    Ok(pool)
+}
+
+/// Wait before the first retry of a failed open; doubled for each retry.
+#[cfg(feature = "index")]
+const IO_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Run `op`, an open or create of `path`, retrying transient failures (as on
+/// a flaky network filesystem) up to `retries` times with doubling backoff.
+/// Errors retrying cannot fix, such as a missing file or denied permission,
+/// are returned at once.
+#[cfg(feature = "index")]
+pub fn retry_io<T>(
+    path: &Path,
+    retries: u32,
+    mut op: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut delay = IO_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                warn!(
+                    "{}: {}; retrying in {} ms ({}/{})",
+                    path.display(),
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
         }
-    });
+    }
+}
+
+/// Raw errno values of transient failures without an `ErrorKind` of their
+/// own: EIO, EAGAIN and ESTALE (a stale NFS handle).
+#[cfg(all(feature = "index", target_os = "linux"))]
+const TRANSIENT_ERRNOS: &[i32] = &[5, 11, 116];
+#[cfg(all(feature = "index", target_os = "macos"))]
+const TRANSIENT_ERRNOS: &[i32] = &[5, 35, 70];
+#[cfg(all(feature = "index", not(any(target_os = "linux", target_os = "macos"))))]
+const TRANSIENT_ERRNOS: &[i32] = &[];
+
+/// Only failures known to clear up on their own are retried; anything else,
+/// including errors this list does not know, is returned at once.
+#[cfg(feature = "index")]
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        Interrupted | TimedOut | WouldBlock | ConnectionReset
+    ) || e
+        .raw_os_error()
+        .is_some_and(|code| TRANSIENT_ERRNOS.contains(&code))
+}
+
+/// Soft time limit set by `--max_runtime`, checked between targets so that a
+/// run can stop early and still write what it has.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// Expires `seconds` from now; `None` never expires.
+    pub fn after(seconds: Option<u64>) -> Self {
+        Deadline(seconds.map(|s| Instant::now() + Duration::from_secs(s)))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.0.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_use_their_own_thread_counts() {
+        let first = thread_pool(2).unwrap();
+        let second = thread_pool(3).unwrap();
+        assert_eq!(first.install(rayon::current_num_threads), 2);
+        assert_eq!(second.install(rayon::current_num_threads), 3);
+        // The first pool keeps its count after the second is built
+        assert_eq!(first.install(rayon::current_num_threads), 2);
+    }
+
+    #[cfg(feature = "index")]
+    #[test]
+    fn index_list_skips_comments_blank_lines_and_missing_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("present.cbl");
+        std::fs::write(&present, b"").unwrap();
+        let list = dir.path().join("indexes.txt");
+        std::fs::write(
+            &list,
+            format!(
+                "# off-target indexes\n\n  {}  \n   \n#{}\n{}\n",
+                present.display(),
+                present.display(),
+                dir.path().join("missing.cbl").display()
+            ),
+        )
+        .unwrap();
+        assert_eq!(read_index_list(list.to_str().unwrap()).unwrap(), [present]);
+    }
+
+    #[cfg(feature = "index")]
+    #[test]
+    fn only_known_transient_errors_are_retried() {
+        use std::io::{Error, ErrorKind};
+        assert!(is_transient(&Error::from(ErrorKind::TimedOut)));
+        assert!(is_transient(&Error::from(ErrorKind::Interrupted)));
+        assert!(!is_transient(&Error::from(ErrorKind::NotFound)));
+        assert!(!is_transient(&Error::from(ErrorKind::Other)));
+        #[cfg(target_os = "linux")]
+        assert!(is_transient(&Error::from_raw_os_error(116)));
+
+        let mut attempts = 0;
+        let result: std::io::Result<()> = retry_io(Path::new("x"), 3, || {
+            attempts += 1;
+            Err(Error::from(ErrorKind::PermissionDenied))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn missing_directory_is_a_clear_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        for recursive in [false, true] {
+            let error = find_files_with_extensions(&missing, &["fa"], recursive).unwrap_err();
+            assert!(
+                error.to_string().starts_with("Cannot read directory"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_subdirectory_is_skipped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("top.fa"), ">a\nACGT\n").unwrap();
+        let locked = dir.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::write(locked.join("inner.fa"), ">b\nACGT\n").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let found = find_files_with_extensions(dir.path(), &["fa"], true);
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Root can still read the subdirectory, so only the top-level file is certain
+        assert!(found.unwrap().contains(&dir.path().join("top.fa")));
+    }
 }
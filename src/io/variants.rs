@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::error::{Result, VisiogenError};
+
+/// Known variant sites per sequence, as merged 0-based half-open intervals.
+#[derive(Debug, Default)]
+pub struct VariantSites {
+    intervals: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl VariantSites {
+    pub fn len(&self) -> usize {
+        self.intervals.values().map(Vec::len).sum()
+    }
+
+    /// Whether any site on `seqid` overlaps the 0-based half-open `start..end`.
+    pub fn overlaps(&self, seqid: &str, start: usize, end: usize) -> bool {
+        let Some(intervals) = self.intervals.get(seqid) else {
+            return false;
+        };
+        let i = intervals.partition_point(|&(_, site_end)| site_end <= start);
+        i < intervals.len() && intervals[i].0 < end
+    }
+}
+
+/// Read variant positions from a BED file (`.bed`, 0-based half-open) or a
+/// VCF (anything else; `POS` is 1-based and the site spans the REF allele).
+pub fn read_variant_sites(path: &str) -> Result<VariantSites> {
+    let file = File::open(path)
+        .map_err(|e| VisiogenError::VariantParseError(format!("Failed to open {}: {}", path, e)))?;
+    let is_bed = path.to_ascii_lowercase().ends_with(".bed");
+
+    let mut intervals: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let malformed = || {
+            VisiogenError::VariantParseError(format!(
+                "{} line {}: expected {}",
+                path,
+                line_no + 1,
+                if is_bed {
+                    "chrom, start and end"
+                } else {
+                    "CHROM, POS, ID and REF"
+                }
+            ))
+        };
+        let number = |i: usize| -> Result<usize> {
+            fields
+                .get(i)
+                .and_then(|f| f.trim().parse().ok())
+                .ok_or_else(malformed)
+        };
+
+        let (start, end) = if is_bed {
+            (number(1)?, number(2)?)
+        } else {
+            let pos = number(1)?;
+            let ref_len = fields.get(3).ok_or_else(malformed)?.len().max(1);
+            (pos.saturating_sub(1), pos.saturating_sub(1) + ref_len)
+        };
+        intervals
+            .entry(fields[0].to_string())
+            .or_default()
+            .push((start, end.max(start + 1)));
+    }
+
+    for sites in intervals.values_mut() {
+        sites.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(sites.len());
+        for &(start, end) in sites.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        *sites = merged;
+    }
+
+    Ok(VariantSites { intervals })
+}
@@ -1,9 +1,24 @@
-use chrono::Local;
+use chrono::{Local, Utc};
+use log::kv::{Error as KvError, Key, Value, VisitSource};
 use log::*;
 use simplelog::*;
 use std::fs::File;
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
 
-pub fn set_up_logging() {
+use crate::cli::LogFormat;
+use crate::core::probes::json_string;
+
+/// Pipeline stage attached to every log file record as its `stage` field.
+static STAGE: RwLock<&str> = RwLock::new("setup");
+
+/// Mark the start of a pipeline stage (e.g. `tile`, `filter`, `off_target`);
+/// records logged from then on, on any thread, carry it as `stage`.
+pub fn set_stage(stage: &'static str) {
+    *STAGE.write().unwrap() = stage;
+}
+
+pub fn set_up_logging(format: LogFormat) {
     let current_time = Local::now().format("%m-%d_%H-%M-%S").to_string();
     let log_filename = format!("visiogen_{}.log", current_time);
 
@@ -14,11 +29,107 @@ pub fn set_up_logging() {
             TerminalMode::Mixed,
             ColorChoice::Auto,
         ),
-        WriteLogger::new(
-            LevelFilter::Info,
-            Config::default(),
-            File::create(log_filename).unwrap(),
-        ),
+        Box::new(FieldLogger {
+            level: LevelFilter::Info,
+            format,
+            file: Mutex::new(File::create(log_filename).unwrap()),
+        }),
     ])
     .unwrap();
 }
+
+/// Log file writer that keeps the key-value fields of each record (`gene`,
+/// `kmer`, ...) plus the current `stage`, so the log can be filtered by
+/// them. The text format is the terminal's with the fields appended after
+/// ` | `; values with spaces, quotes or `=` are quoted.
+struct FieldLogger {
+    level: LevelFilter,
+    format: LogFormat,
+    file: Mutex<File>,
+}
+
+impl FieldLogger {
+    fn render(&self, record: &Record) -> String {
+        let time = Utc::now().format("%H:%M:%S").to_string();
+        let mut fields = vec![("stage".to_string(), STAGE.read().unwrap().to_string())];
+        let _ = record.key_values().visit(&mut Fields(&mut fields));
+
+        match self.format {
+            LogFormat::Text => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, logfmt_value(value)))
+                    .collect();
+                format!(
+                    "{} [{}] {} | {}\n",
+                    time,
+                    record.level(),
+                    record.args(),
+                    fields.join(" ")
+                )
+            }
+            LogFormat::Json => {
+                let mut entries = vec![
+                    ("time".to_string(), time),
+                    ("level".to_string(), record.level().to_string()),
+                    ("message".to_string(), record.args().to_string()),
+                ];
+                entries.extend(fields);
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+                    .collect();
+                format!("{{{}}}\n", entries.join(","))
+            }
+        }
+    }
+}
+
+impl Log for FieldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = self.render(record);
+            let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+impl SharedLogger for FieldLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+/// Collects a record's key-value pairs as strings, after those already held.
+struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+fn logfmt_value(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("{:?}", value)
+    } else {
+        value.to_string()
+    }
+}
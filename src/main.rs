@@ -1,5 +1,5 @@
-#![allow(incomplete_features)]
-#![feature(generic_const_exprs)]
+#![cfg_attr(feature = "index", allow(incomplete_features))]
+#![cfg_attr(feature = "index", feature(generic_const_exprs))]
 
 mod cli;
 mod core;
@@ -8,45 +8,383 @@ mod io;
 mod logging;
 mod processing;
 
-use crate::cli::{parse_args, Args, BuildArgs, Commands, GffArgs, KmerOptions};
-use crate::core::probes::{GeneKmers, Probes};
+use crate::cli::{
+    parse_args, AnnotateArgs, Args, BuildArgs, Commands, FastaCase, GffArgs, GraphArgs,
+    KmerOptions, OffTargetOptions, OutputFormat, ProbesFromFastaArgs, StatsArgs, Tiling,
+};
+use crate::core::probes::{self, kmer_gene_index, GeneKmers, ProbeSet, Probes, ScoreWeights};
+use crate::core::seq::melting_temperature;
+use crate::core::summary::{RunSummary, SkipReason};
 use crate::error::{Result, VisiogenError};
-use crate::io::output;
-use crate::processing::{gff, graph, index};
-use log::info;
-use std::collections::HashMap;
+use crate::io::gene_options::{self, GeneOptions};
+use crate::io::utils::{self, Deadline};
+use crate::io::variants::{self, VariantSites};
+use crate::io::{fasta, output};
+use crate::processing::graph::{Segment, SegmentEnd};
+#[cfg(feature = "index")]
+use crate::processing::index;
+use crate::processing::{bed, gff, graph, pan};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
 
 fn run(args: Args) -> Result<()> {
+    validate_kmer_options(&args.kmer_options, &args.off_target)?;
+    if args.tiling == Tiling::Contiguous && args.kmer_options.variable_length().is_some() {
+        return Err(VisiogenError::InvalidArgument(
+            "--tiling contiguous needs fixed-length probes; drop --kmer_min/--kmer_max".to_string(),
+        ));
+    }
+    if !(0.0..=1.0).contains(&args.off_target.specificity_weight) {
+        return Err(VisiogenError::InvalidArgument(
+            "--specificity_weight must be between 0 and 1".to_string(),
+        ));
+    }
+    if !(0.0..=1.0).contains(&args.min_pan_fraction) {
+        return Err(VisiogenError::InvalidArgument(
+            "--min_pan_fraction must be between 0 and 1".to_string(),
+        ));
+    }
+    if args.collapse_duplicates && args.format != OutputFormat::Fasta {
+        return Err(VisiogenError::InvalidArgument(
+            "--collapse_duplicates writes FASTA; drop --format or set it to fasta".to_string(),
+        ));
+    }
+    if args.kmer_options.skip_masked && args.fasta_case != FastaCase::Preserve {
+        return Err(VisiogenError::InvalidArgument(
+            "--skip_masked needs --fasta_case preserve; changing the case removes the soft-masking"
+                .to_string(),
+        ));
+    }
+    if args.tiling == Tiling::Contiguous && args.keep_n_per_window > 0 {
+        return Err(VisiogenError::InvalidArgument(
+            "--keep_n_per_window works with --tiling best".to_string(),
+        ));
+    }
+
+    if let Some(tmpdir) = &args.tmpdir {
+        tempfile::tempfile_in(tmpdir).map_err(|e| {
+            VisiogenError::InvalidArgument(format!("--tmpdir {} is not writable: {}", tmpdir, e))
+        })?;
+    }
+
+    // The command runs on its own pool rather than the global one, so each
+    // phase (and any later one in the same process) honours its own count
+    let pool = utils::thread_pool(args.threads)
+        .map_err(|e| VisiogenError::Other(format!("Failed to start the thread pool: {}", e)))?;
+    info!("Using {} thread(s)", pool.current_num_threads());
+    pool.install(|| run_command(&args))
+}
+
+fn run_command(args: &Args) -> Result<()> {
+    logging::set_stage(match &args.command {
+        Commands::Gff(_) | Commands::Graph(_) | Commands::ProbesFromFasta(_) => "input",
+        Commands::Build(_) => "build",
+        Commands::Stats(_) => "stats",
+        Commands::Annotate(_) => "annotate",
+        Commands::Doctor => "doctor",
+    });
     match &args.command {
-        Commands::Gff(gff_args) => run_probe_command(&args, gff_args),
-        Commands::Build(build_args) => run_build_command(&args, build_args),
+        Commands::Gff(gff_args) => run_gff_command(args, gff_args),
+        Commands::Graph(graph_args) => run_graph_command(args, graph_args),
+        Commands::ProbesFromFasta(fasta_args) => run_probes_from_fasta_command(args, fasta_args),
+        Commands::Build(build_args) => run_build_command(args, build_args),
+        Commands::Stats(stats_args) => run_stats_command(args, stats_args),
+        Commands::Annotate(annotate_args) => run_annotate_command(annotate_args),
+        Commands::Doctor => run_doctor_command(args),
+    }
+}
+
+/// Report any failure reading the GFF as a GFF parse error, without wrapping
+/// one that already is.
+fn as_gff_error(e: VisiogenError) -> VisiogenError {
+    match e {
+        VisiogenError::GffParseError(_) => e,
+        other => VisiogenError::GffParseError(other.to_string()),
     }
 }
 
-fn run_probe_command(args: &Args, gff_args: &GffArgs) -> Result<()> {
-    let _gene_coords =
-        gff::coords_from_gene_name(&gff_args.in_gff, &gff_args.genes).map_err(|e| {
-            VisiogenError::GffParseError(format!(
-                "off_target_directory required for build command {}",
-                e
+fn run_gff_command(args: &Args, gff_args: &GffArgs) -> Result<()> {
+    // Read once: the BED may be a stream on stdin
+    let bed_targets = gff_args
+        .bed
+        .as_deref()
+        .map(bed::read_bed_targets)
+        .transpose()?;
+
+    let genes = if gff_args.all_genes() {
+        let names = match (&bed_targets, &gff_args.in_gff) {
+            (Some(targets), _) => bed::target_names(targets),
+            (None, Some(gff_path)) => {
+                gff::feature_names(gff_path, &gff_args.feature_type).map_err(as_gff_error)?
+            }
+            (None, None) => return Err(missing_annotation()),
+        };
+        warn!(
+            "Designing probes for all {} '{}' features; expect a long runtime and up to {} probes of output",
+            names.len(),
+            gff_args.feature_type,
+            names.len() * args.n_count as usize
+        );
+        names
+    } else {
+        gff_args.gene_list()
+    };
+    let genes = exclude_genes(genes, &gff_args.excluded_genes()?);
+
+    let mut reference = match (&gff_args.in_fasta, &gff_args.in_gff) {
+        (Some(fasta_path), _) => fasta::read_fasta(fasta_path)?,
+        (None, Some(gff_path)) => {
+            let embedded = gff::embedded_fasta(gff_path).map_err(as_gff_error)?;
+            let reference = embedded.ok_or_else(|| {
+                VisiogenError::MissingArgument(format!(
+                    "--fasta (the reference), as {} has no ##FASTA section",
+                    gff_path
+                ))
+            })?;
+            info!(
+                "Using the {} sequence(s) embedded in {}",
+                reference.len(),
+                gff_path
+            );
+            reference
+        }
+        (None, None) => {
+            return Err(VisiogenError::MissingArgument(
+                "--fasta (the reference) is required with --bed".to_string(),
             ))
+        }
+    };
+    fasta::set_case(reference.values_mut(), args.fasta_case);
+
+    if let (Some(fasta_path), Some(gff_path)) = (&gff_args.in_fasta, &gff_args.in_gff) {
+        check_same_assembly(gff_path, fasta_path, &reference, args.strict)?;
+    }
+
+    let mut gene_index = match (&bed_targets, &gff_args.in_gff) {
+        (Some(targets), _) => bed::build_target_index(targets),
+        (None, Some(gff_path)) => {
+            gff::build_gene_index(gff_path, &gff_args.feature_type).map_err(as_gff_error)?
+        }
+        (None, None) => return Err(missing_annotation()),
+    };
+
+    // --between replaces the gene list with the one region between the pair
+    let genes = match &gff_args.between {
+        Some(between) => {
+            let (first, second) = between_pair(between)?;
+            let (name, region) = gff::between_region(&gene_index, &first, &second)?;
+            gene_index.insert(name.clone(), region);
+            vec![name]
+        }
+        None => genes,
+    };
+
+    let options = gene_options(args)?;
+    logging::set_stage("tile");
+    let (gene_kmers, skipped) = if gff_args.exon_junctions {
+        let gff_path = gff_args.in_gff.as_ref().ok_or_else(|| {
+            VisiogenError::InvalidArgument(
+                "--exon_junctions needs exon features from a GFF (-a), not --bed".to_string(),
+            )
         })?;
+        let exon_index =
+            gff::build_exon_index(gff_path, &gff_args.feature_type).map_err(as_gff_error)?;
+        let (gene_kmers, transcripts, unprocessed) = gff::generate_junction_kmers(
+            &exon_index,
+            &reference,
+            &genes,
+            &options,
+            Deadline::after(args.max_runtime),
+        );
+        warn_unprocessed(&unprocessed);
+        // Probes are placed on the spliced transcripts, e.g. for --flank
+        reference.extend(transcripts);
+        (gene_kmers, Vec::new())
+    } else {
+        let (gene_kmers, skipped, unprocessed) = gff::generate_gene_kmers(
+            &gene_index,
+            &reference,
+            &genes,
+            &options,
+            Deadline::after(args.max_runtime),
+        )?;
+        warn_unprocessed(&unprocessed);
+        (gene_kmers, skipped)
+    };
+
+    let summary = RunSummary {
+        targets_requested: genes.len(),
+        targets_found: genes.iter().filter(|g| gene_index.contains_key(*g)).count(),
+        skipped_targets: skipped,
+        ..Default::default()
+    };
+
+    let total_kmers: usize = gene_kmers.iter().map(|f| f.kmers.len()).sum();
+    info!(
+        "Generated kmers for {} of {} genes (total kmers: {}, avg per gene: {:.2})",
+        gene_kmers.len(),
+        genes.len(),
+        total_kmers,
+        total_kmers as f64 / gene_kmers.len().max(1) as f64
+    );
+
+    design_probes(args, &options, gene_kmers, &reference, summary)?;
+    Ok(())
+}
+
+/// Warn when the GFF and FASTA look like different assemblies, which would
+/// otherwise silently place probes at the wrong coordinates; an error with
+/// `--strict`.
+fn check_same_assembly(
+    gff_path: &str,
+    fasta_path: &str,
+    reference: &HashMap<String, String>,
+    strict: bool,
+) -> Result<()> {
+    let (annotation, _) = gff::read_gff_sections(gff_path).map_err(as_gff_error)?;
+    let problems = gff::assembly_mismatches(&annotation, reference).map_err(as_gff_error)?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        warn!("{} vs {}: {}", gff_path, fasta_path, problem);
+    }
+    if strict {
+        return Err(VisiogenError::InvalidArgument(format!(
+            "{} and {} do not look like the same assembly: {}",
+            gff_path,
+            fasta_path,
+            problems.join("; ")
+        )));
+    }
+    Ok(())
+}
+
+fn missing_annotation() -> VisiogenError {
+    VisiogenError::MissingArgument("-a/--annotation or --bed".to_string())
+}
+
+/// Split `--between` into its two gene names.
+fn between_pair(between: &str) -> Result<(String, String)> {
+    let names: Vec<&str> = between.split(',').map(str::trim).collect();
+    match names.as_slice() {
+        [a, b] if !a.is_empty() && !b.is_empty() && a != b => Ok((a.to_string(), b.to_string())),
+        _ => Err(VisiogenError::InvalidArgument(format!(
+            "--between expects two different gene names separated by a comma, got '{}'",
+            between
+        ))),
+    }
+}
+
+/// Drop `excluded` names from the target set, warning about any that were not
+/// targets to begin with.
+fn exclude_genes(genes: Vec<String>, excluded: &[String]) -> Vec<String> {
+    if excluded.is_empty() {
+        return genes;
+    }
+
+    let targets: HashSet<&str> = genes.iter().map(String::as_str).collect();
+    for name in excluded.iter().filter(|g| !targets.contains(g.as_str())) {
+        warn!(gene = name.as_str(); "Excluded gene {} is not in the target set", name);
+    }
+
+    let excluded: HashSet<&str> = excluded.iter().map(String::as_str).collect();
+    let before = genes.len();
+    let kept: Vec<String> = genes
+        .into_iter()
+        .filter(|g| !excluded.contains(g.as_str()))
+        .collect();
+    info!(
+        "Excluded {} of {} target gene(s)",
+        before - kept.len(),
+        before
+    );
+    kept
+}
 
-    let graph = graph::parse_gfa_file(&args.gfa_path)
+fn run_graph_command(args: &Args, graph_args: &GraphArgs) -> Result<()> {
+    let mut graph = graph::parse_gfa_file(&graph_args.gfa_path)
         .map_err(|e| VisiogenError::GfaParseError(format!("Failed to read GFA file: {}", e)))?;
+    fasta::set_case(
+        graph
+            .segments
+            .iter_mut()
+            .map(|segment| &mut segment.sequence),
+        args.fasta_case,
+    );
+    info!(
+        "Read {} as {:?}: {} segment(s), {} path(s)",
+        graph_args.gfa_path,
+        graph.version,
+        graph.segments.len(),
+        graph.paths.len()
+    );
+
+    let duplicates = graph.duplicate_segment_names();
+    if args.strict && !duplicates.is_empty() {
+        return Err(VisiogenError::GfaParseError(format!(
+            "duplicate segment names in {}: {}",
+            graph_args.gfa_path,
+            duplicates.join(", ")
+        )));
+    }
+
+    if graph.paths.len() < graph_args.min_paths {
+        return Err(VisiogenError::GfaParseError(format!(
+            "{} has {} path(s) but --min_paths is {}; core segments are not meaningful for so few paths (does the GFA have P/W lines?)",
+            graph_args.gfa_path,
+            graph.paths.len(),
+            graph_args.min_paths
+        )));
+    }
+
+    let targets = if graph_args.bubble_anchors {
+        anchor_targets(&graph, graph_args)?
+    } else {
+        let segments = if graph_args.accessory {
+            accessory_targets(&graph, graph_args)?
+        } else {
+            core_targets(&graph, graph_args)?
+        };
+        segments.into_iter().map(SegmentTarget::whole).collect()
+    };
+
+    let summary = RunSummary {
+        targets_requested: graph.segments.len(),
+        targets_found: targets.len(),
+        ..Default::default()
+    };
 
-    let segment_kmers: Vec<GeneKmers> = graph
-        .core_segment_structs()
+    let options = gene_options(args)?;
+    let deadline = Deadline::after(args.max_runtime);
+    let mut unprocessed = Vec::new();
+    logging::set_stage("tile");
+    let segment_kmers: Vec<GeneKmers> = targets
         .iter()
-        .map(|segment| GeneKmers {
-            gene: segment.name.clone(),
-            start: 1,
-            end: 1 + segment.sequence.len() as u64,
-            kmers: Probes::generate_probes(&segment.sequence, args.kmer_options.kmer_size, 0),
-            strand: "+".to_string(),
-            kmer_hits: HashMap::new(),
+        .filter_map(|target| {
+            if deadline.expired() {
+                unprocessed.push(target.name.clone());
+                return None;
+            }
+            let range = target.range.clone();
+            Some(GeneKmers {
+                gene: target.name.clone(),
+                seqid: target.segment.name.clone(),
+                // 1-based inclusive, like GFF
+                start: range.start as u64 + 1,
+                end: range.end as u64,
+                kmers: tile_target(
+                    &target.name,
+                    &target.segment.sequence[range.clone()],
+                    range.start,
+                    options.for_gene(&target.name),
+                ),
+                strand: "+".to_string(),
+                kmer_hits: HashMap::new(),
+            })
         })
         .collect();
+    warn_unprocessed(&unprocessed);
 
     let total_kmers: usize = segment_kmers.iter().map(|f| f.kmers.len()).sum();
     info!(
@@ -56,62 +394,950 @@ fn run_probe_command(args: &Args, gff_args: &GffArgs) -> Result<()> {
         total_kmers as f64 / segment_kmers.len().max(1) as f64
     );
 
-    let filtered_kmers = apply_kmer_filters(segment_kmers, &args.kmer_options);
+    // Segments are their own reference for --flank
+    let reference: HashMap<String, String> = if args.flank > 0 {
+        targets
+            .iter()
+            .map(|target| (target.segment.name.clone(), target.segment.sequence.clone()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let written = design_probes(args, &options, segment_kmers, &reference, summary)?;
 
-    let final_probes = select_best_probes(filtered_kmers, args.n_count);
+    if graph_args.path_coords {
+        logging::set_stage("output");
+        report_path_coords(&graph, &written);
+    }
+
+    Ok(())
+}
+
+/// A stretch of one segment tiled in graph mode: the whole segment, or with
+/// `--bubble_anchors` the window of a core segment next to a bubble.
+struct SegmentTarget<'a> {
+    name: String,
+    segment: &'a Segment,
+    /// 0-based half-open range within the segment
+    range: std::ops::Range<usize>,
+}
+
+impl<'a> SegmentTarget<'a> {
+    fn whole(segment: &'a Segment) -> Self {
+        SegmentTarget {
+            name: segment.name.clone(),
+            segment,
+            range: 0..segment.sequence.len(),
+        }
+    }
+}
+
+/// The `--anchor_window` bp of every core segment end bordering a bubble,
+/// named `<segment>_<start|end>_<bubble>` so each probe names the bubble it
+/// anchors.
+fn anchor_targets<'a>(
+    graph: &'a graph::Gfa,
+    graph_args: &GraphArgs,
+) -> Result<Vec<SegmentTarget<'a>>> {
+    let bubbles = graph.bubbles();
+    if bubbles.is_empty() {
+        return Err(VisiogenError::GfaParseError(format!(
+            "No bubbles with core anchors found in {} ({} segments, {} link(s)): bubble anchors need L lines linking core segments to non-core ones",
+            graph_args.gfa_path,
+            graph.segments.len(),
+            graph.links.len()
+        )));
+    }
+
+    let segments: HashMap<&str, &Segment> = graph
+        .segments
+        .iter()
+        .map(|segment| (segment.name.as_str(), segment))
+        .collect();
+    let mut targets = Vec::new();
+    for bubble in &bubbles {
+        let anchors: Vec<String> = bubble
+            .anchors
+            .iter()
+            .map(|(name, end)| format!("{} ({})", name, end.as_str()))
+            .collect();
+        info!(
+            "{}: {} variable segment(s) ({}), anchored by {}",
+            bubble.name,
+            bubble.segments.len(),
+            bubble.segments.join(", "),
+            anchors.join(", ")
+        );
+        for (name, end) in &bubble.anchors {
+            let Some(&segment) = segments.get(name.as_str()) else {
+                continue;
+            };
+            let len = segment.sequence.len();
+            let window = graph_args.anchor_window.min(len);
+            let range = match end {
+                SegmentEnd::Start => 0..window,
+                SegmentEnd::End => len - window..len,
+            };
+            targets.push(SegmentTarget {
+                name: format!("{}_{}_{}", name, end.as_str(), bubble.name),
+                segment,
+                range,
+            });
+        }
+    }
+    info!(
+        "{} bubble(s) give {} anchor window(s) of up to {} bp",
+        bubbles.len(),
+        targets.len(),
+        graph_args.anchor_window
+    );
+
+    Ok(targets)
+}
+
+/// Segments present exactly once in every path, plus the ambiguous ones when
+/// `--include_ambiguous` is set.
+fn core_targets<'a>(graph: &'a graph::Gfa, graph_args: &GraphArgs) -> Result<Vec<&'a Segment>> {
+    let mut core_segments = graph.core_segment_structs();
+
+    let ambiguous = graph.ambiguous_segments();
+    if !ambiguous.is_empty() {
+        warn!(
+            "{} segment(s) appear in every path but more than once in some ({}): {}",
+            ambiguous.len(),
+            if graph_args.include_ambiguous {
+                "tiling them"
+            } else {
+                "excluded; use --include_ambiguous to tile them"
+            },
+            ambiguous.join(", ")
+        );
+        if graph_args.include_ambiguous {
+            core_segments.extend(graph.segments_named(&ambiguous));
+        }
+    }
+
+    if core_segments.is_empty() {
+        let reason = if graph.paths.is_empty() {
+            "the GFA has no path (P/W) lines, so core segments cannot be determined".to_string()
+        } else {
+            format!(
+                "no segment appears exactly once in all {} paths",
+                graph.paths.len()
+            )
+        };
+        return Err(VisiogenError::GfaParseError(format!(
+            "No core segments found in {} ({} segments): {}",
+            graph_args.gfa_path,
+            graph.segments.len(),
+            reason
+        )));
+    }
+
+    Ok(core_segments)
+}
+
+/// Segments present in at most `--accessory_max_fraction` of the paths.
+fn accessory_targets<'a>(
+    graph: &'a graph::Gfa,
+    graph_args: &GraphArgs,
+) -> Result<Vec<&'a Segment>> {
+    let max_fraction = graph_args.accessory_max_fraction;
+    if !(0.0..1.0).contains(&max_fraction) {
+        return Err(VisiogenError::InvalidArgument(format!(
+            "--accessory_max_fraction must be at least 0 and below 1, got {}",
+            max_fraction
+        )));
+    }
+
+    let accessory = graph.accessory_segments(max_fraction);
+    if accessory.is_empty() {
+        return Err(VisiogenError::GfaParseError(format!(
+            "No accessory segments found in {} ({} segments): no segment is visited by between one path and {}% of the {} paths",
+            graph_args.gfa_path,
+            graph.segments.len(),
+            max_fraction * 100.0,
+            graph.paths.len()
+        )));
+    }
+    info!(
+        "{} accessory segment(s) are visited by at most {}% of {} paths",
+        accessory.len(),
+        max_fraction * 100.0,
+        graph.paths.len()
+    );
+
+    Ok(graph.segments_named(&accessory))
+}
+
+/// Log where each written probe falls in every path that visits its segment.
+fn report_path_coords(graph: &graph::Gfa, written: &[GeneKmers]) {
+    let placements = graph.segment_path_offsets();
+
+    for gk in written {
+        let Some(visits) = placements.get(&gk.seqid) else {
+            continue;
+        };
+        for probe in &gk.kmers {
+            for &loc in &probe.locations {
+                for visit in visits {
+                    let (start, end, strand) = visit.interval(loc, probe.kmer.len());
+                    info!(
+                        gene = gk.gene.as_str(), kmer = probe.kmer.as_str();
+                        "Probe {} (segment {}, offset {}): {}:{}-{} ({})",
+                        probe.kmer,
+                        gk.seqid,
+                        loc,
+                        visit.path,
+                        start + 1,
+                        end,
+                        strand
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn run_probes_from_fasta_command(args: &Args, fasta_args: &ProbesFromFastaArgs) -> Result<()> {
+    let mut records = fasta::read_fasta_records(&fasta_args.in_fasta)?;
+    fasta::dedup_record_ids(&mut records);
+    fasta::set_case(records.iter_mut().map(|(_, seq)| seq), args.fasta_case);
+    let options = gene_options(args)?;
+
+    let mut summary = RunSummary {
+        targets_requested: records.len(),
+        targets_found: records.len(),
+        ..Default::default()
+    };
+
+    let deadline = Deadline::after(args.max_runtime);
+    let mut unprocessed = Vec::new();
+    logging::set_stage("tile");
+    let target_kmers: Vec<GeneKmers> = records
+        .iter()
+        .filter_map(|(id, sequence)| {
+            if deadline.expired() {
+                unprocessed.push(id.clone());
+                return None;
+            }
+            let kmer_options = options.for_gene(id);
+            let kmer_size = kmer_options.min_probe_len();
+            if sequence.len() < kmer_size && !kmer_options.allow_short {
+                summary.skip(id, SkipReason::TooShort);
+                warn!(
+                    gene = id.as_str();
+                    "Record {} is shorter ({} bp) than the kmer size ({}); see --allow_short",
+                    id,
+                    sequence.len(),
+                    kmer_size
+                );
+                return None;
+            }
+            let kmers = tile_target(id, sequence, 0, kmer_options);
+            Some(GeneKmers {
+                gene: id.clone(),
+                seqid: id.clone(),
+                start: 1,
+                end: sequence.len() as u64,
+                kmers,
+                strand: "+".to_string(),
+                kmer_hits: HashMap::new(),
+            })
+        })
+        .collect();
+    warn_unprocessed(&unprocessed);
 
-    output::write_filtered_kmers(final_probes, args, "probes")?;
+    let total_kmers: usize = target_kmers.iter().map(|f| f.kmers.len()).sum();
+    info!(
+        "Generated kmers for {} of {} records (total kmers: {}, avg per record: {:.2})",
+        target_kmers.len(),
+        summary.targets_requested,
+        total_kmers,
+        total_kmers as f64 / target_kmers.len().max(1) as f64
+    );
 
+    let reference: HashMap<String, String> = records.into_iter().collect();
+    design_probes(args, &options, target_kmers, &reference, summary)?;
     Ok(())
 }
 
+/// Attach `flank` bases of reference context to every selected probe.
+fn add_flanks(
+    mut gene_kmers: Vec<GeneKmers>,
+    reference: &HashMap<String, String>,
+    flank: usize,
+) -> Vec<GeneKmers> {
+    for gk in gene_kmers.iter_mut() {
+        match reference.get(&gk.seqid) {
+            Some(contig) => gk.add_flanks(contig, flank),
+            None => warn!(
+                gene = gk.gene.as_str();
+                "Sequence '{}' for {} not found; writing its probes without flanks",
+                gk.seqid, gk.gene
+            ),
+        }
+    }
+    gene_kmers
+}
+
+/// Warn that `--max_runtime` cut the run short, naming the targets that were
+/// never tiled; the rest are still designed and written.
+fn warn_unprocessed(unprocessed: &[String]) {
+    if unprocessed.is_empty() {
+        return;
+    }
+    warn!(
+        "--max_runtime reached; writing partial results. {} target(s) were not processed: {}",
+        unprocessed.len(),
+        unprocessed.join(", ")
+    );
+}
+
+/// Tile a whole target sequence, warning when it has no room for a window.
+/// Locations count from `start_offset`. With `--allow_short`, a target
+/// shorter than the kmer size is one probe.
+fn tile_target(
+    name: &str,
+    sequence: &str,
+    start_offset: usize,
+    kmer_options: &KmerOptions,
+) -> ProbeSet {
+    if kmer_options.allow_short && sequence.len() < kmer_options.min_probe_len() {
+        return Probes::whole(sequence, kmer_options, start_offset);
+    }
+    let kmers = Probes::tile(sequence, kmer_options, start_offset);
+    if kmers.is_empty() {
+        warn!(
+            gene = name;
+            "Target {} ({} bp) produced no candidate probes; it may be too short for a {}-mer with --edge_trim {}",
+            name,
+            sequence.len(),
+            kmer_options.min_probe_len(),
+            kmer_options.edge_trim
+        );
+    }
+    kmers
+}
+
+/// Shared tail of every probe-producing command: filter, select and write.
+/// Returns the probes that were written.
+fn design_probes(
+    args: &Args,
+    options: &GeneOptions,
+    gene_kmers: Vec<GeneKmers>,
+    reference: &HashMap<String, String>,
+    mut summary: RunSummary,
+) -> Result<Vec<GeneKmers>> {
+    summary.targets_with_candidates = gene_kmers.iter().filter(|gk| !gk.kmers.is_empty()).count();
+    for gk in &gene_kmers {
+        let target_len = (gk.end + 1).saturating_sub(gk.start) as usize;
+        let short = target_len < options.for_gene(&gk.gene).min_probe_len();
+        if gk.kmers.is_empty() {
+            let reason = if short {
+                SkipReason::TooShort
+            } else {
+                SkipReason::NoCandidates
+            };
+            summary.skip(&gk.gene, reason);
+        } else if short {
+            summary.short_targets.push(gk.gene.clone());
+        }
+    }
+    summary.candidate_probes = count_probes(&gene_kmers);
+    if let Some(path) = &args.dump_kmers {
+        output::dump_kmers(&gene_kmers, path)?;
+    }
+
+    let variant_sites = match &args.avoid_vcf {
+        Some(path) => {
+            let sites = variants::read_variant_sites(path)?;
+            info!(
+                "Loaded {} variant site(s) to avoid from {}",
+                sites.len(),
+                path
+            );
+            Some(sites)
+        }
+        None => None,
+    };
+    let avoid_regions = |gene_kmers: Vec<GeneKmers>| {
+        let gene_kmers = match &variant_sites {
+            Some(sites) => remove_variant_overlaps(gene_kmers, sites),
+            None => gene_kmers,
+        };
+        if args.contig_edge_buffer > 0 {
+            remove_contig_edge_probes(gene_kmers, reference, args.contig_edge_buffer)
+        } else {
+            gene_kmers
+        }
+    };
+
+    logging::set_stage("filter");
+    let final_probes = match args.tiling {
+        Tiling::Best => {
+            let filtered_kmers = avoid_regions(apply_kmer_filters(gene_kmers, options));
+            let filtered_kmers = screen_pan_targets(filtered_kmers, args)?;
+            let filtered_kmers = report_shared_probes(filtered_kmers, args.drop_shared);
+            summary.after_filters = count_probes(&filtered_kmers);
+            logging::set_stage("select");
+            select_best_probes(filtered_kmers, args, options, &args.score_weights)
+        }
+        Tiling::Contiguous => {
+            let tiles = avoid_regions(select_contiguous_tiles(gene_kmers, options));
+            let tiles = screen_pan_targets(tiles, args)?;
+            let tiles = report_shared_probes(tiles, args.drop_shared);
+            summary.after_filters = count_probes(&tiles);
+            tiles
+        }
+    };
+    summary.after_selection = count_probes(&final_probes);
+    let final_probes = if args.flank > 0 {
+        add_flanks(final_probes, reference, args.flank)
+    } else {
+        final_probes
+    };
+
+    let written = output::write_filtered_kmers(final_probes, args, "probes", &mut summary)?;
+
+    // --summary_only leaves the counts as the run's only output
+    logging::set_stage("summary");
+    summary.report(
+        args.report_format,
+        args.report_file.as_deref(),
+        args.summary_only,
+    )?;
+
+    if count_probes(&written) == 0 {
+        return Err(VisiogenError::NoProbes(format!(
+            "none of {} candidate probe(s) from {} target(s) passed filtering, selection and screening",
+            summary.candidate_probes, summary.targets_found
+        )));
+    }
+
+    Ok(written)
+}
+
+fn count_probes(gene_kmers: &[GeneKmers]) -> usize {
+    gene_kmers.iter().map(|gk| gk.kmers.len()).sum()
+}
+
+#[cfg(feature = "index")]
 fn run_build_command(args: &Args, build_args: &BuildArgs) -> Result<()> {
-    let off_target_dir = args.off_target_directory.as_ref().ok_or_else(|| {
-        VisiogenError::MissingArgument(
+    let off_target_dirs = &args.off_target.off_target_directory;
+    if off_target_dirs.is_empty() {
+        return Err(VisiogenError::MissingArgument(
             "off_target_directory required for build command".to_string(),
+        ));
+    }
+
+    for off_target_dir in off_target_dirs {
+        index::build_indexes_for_all_fastas(
+            std::path::Path::new(off_target_dir),
+            args.build_threads.unwrap_or(args.threads),
+            args.recursive,
+            args.tmpdir.as_deref().map(std::path::Path::new),
+            build_args,
+            args.off_target.prefix_bits,
+            index::FileOptions {
+                io_retries: args.io_retries,
+                fasta_case: args.fasta_case,
+            },
         )
-    })?;
+        .map_err(|e| {
+            VisiogenError::IndexBuildError(format!("Failed to build indexes for fastas {}", e))
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "index"))]
+fn run_build_command(_args: &Args, _build_args: &BuildArgs) -> Result<()> {
+    Err(VisiogenError::Other(
+        "the build command requires visiogen to be compiled with the `index` feature (nightly Rust)"
+            .to_string(),
+    ))
+}
 
-    index::build_indexes_for_all_fastas(
-        std::path::Path::new(off_target_dir),
-        args.threads,
-        build_args.canonical,
-        args.recursive,
+#[cfg(feature = "index")]
+fn run_stats_command(args: &Args, stats_args: &StatsArgs) -> Result<()> {
+    let overlap = index::index_overlap(
+        std::path::Path::new(&stats_args.index_a),
+        std::path::Path::new(&stats_args.index_b),
+        args.io_retries,
     )
-    .map_err(|e| {
-        VisiogenError::IndexBuildError(format!("Failed to build indexes for fastas {}", e))
-    })?;
+    .map_err(|e| VisiogenError::IndexQueryError(format!("Failed to compare indexes: {}", e)))?;
+
+    info!("{}: {} kmers", stats_args.index_a, overlap.kmers_a);
+    info!("{}: {} kmers", stats_args.index_b, overlap.kmers_b);
+    info!("Shared kmers: {}", overlap.shared);
+    info!("Jaccard similarity: {:.4}", overlap.jaccard());
+
+    Ok(())
+}
+
+#[cfg(not(feature = "index"))]
+fn run_stats_command(_args: &Args, _stats_args: &StatsArgs) -> Result<()> {
+    Err(VisiogenError::Other(
+        "the stats command requires visiogen to be compiled with the `index` feature (nightly Rust)"
+            .to_string(),
+    ))
+}
+
+/// Rebuild probe metrics from a probe FASTA, taking targets and locations from its headers.
+fn run_annotate_command(annotate_args: &AnnotateArgs) -> Result<()> {
+    let records = fasta::read_fasta_with_headers(&annotate_args.probes)?;
+    let mut gene_kmers: Vec<GeneKmers> = Vec::new();
+    let mut by_gene: HashMap<String, usize> = HashMap::new();
+    for (header, sequence) in records {
+        let parsed = probes::parse_probe_header(&header);
+        let kmer = parsed.target.unwrap_or(sequence);
+        let end = parsed
+            .locations
+            .iter()
+            .max()
+            .map_or(0, |&loc| loc + kmer.len());
+        let probe = Probes::new(kmer, parsed.locations);
+
+        let i = *by_gene.entry(parsed.gene.clone()).or_insert_with(|| {
+            gene_kmers.push(GeneKmers {
+                gene: parsed.gene.clone(),
+                seqid: parsed.gene.clone(),
+                start: 1,
+                end: 0,
+                kmers: Vec::new(),
+                strand: "+".to_string(),
+                kmer_hits: HashMap::new(),
+            });
+            gene_kmers.len() - 1
+        });
+        let gk = &mut gene_kmers[i];
+        gk.end = gk.end.max(end as u64);
+        gk.kmers.push(probe);
+    }
+    info!(
+        "Read {} probe(s) for {} target(s) from {}",
+        count_probes(&gene_kmers),
+        gene_kmers.len(),
+        annotate_args.probes
+    );
+    if gene_kmers.is_empty() {
+        return Err(VisiogenError::NoProbes(format!(
+            "{} has no records",
+            annotate_args.probes
+        )));
+    }
+
+    let timestamp = chrono::Local::now().format("%d-%m-%H-%M").to_string();
+    output::dump_kmers(&gene_kmers, &format!("annotated_{}.tsv", timestamp))?;
+    output::write_probe_bed(&gene_kmers, &format!("annotated_{}.bed", timestamp))?;
+    Ok(())
+}
+
+/// Print a pass/fail checklist of the environment, failing if any check does.
+fn run_doctor_command(args: &Args) -> Result<()> {
+    let mut checks: Vec<(String, std::result::Result<String, String>)> = Vec::new();
+
+    checks.push((
+        "Build".to_string(),
+        Ok(format!(
+            "{} (features: {})",
+            env!("VISIOGEN_RUSTC_VERSION"),
+            env!("VISIOGEN_FEATURES")
+        )),
+    ));
+
+    checks.push((
+        "Log directory".to_string(),
+        tempfile::tempfile_in(".")
+            .map(|_| "current directory is writable".to_string())
+            .map_err(|e| format!("cannot write logs or output here: {}", e)),
+    ));
+
+    checks.push(("Index round trip".to_string(), index_self_test(args)));
+
+    checks.push((
+        "Threads".to_string(),
+        Ok(format!(
+            "{} (--threads {})",
+            rayon::current_num_threads(),
+            args.threads
+        )),
+    ));
+
+    for dir in &args.off_target.off_target_directory {
+        let found = utils::find_files_in_directories(
+            &[dir.into()],
+            &["fasta", "fa", "cbl"],
+            args.recursive,
+        )
+        .map_err(|e| e.to_string())
+        .and_then(|files| match files.len() {
+            0 => Err("no FASTA or .cbl files found".to_string()),
+            n => Ok(format!("{} FASTA/.cbl file(s)", n)),
+        });
+        checks.push((format!("Off-target directory {}", dir), found));
+    }
 
+    let mut failed = 0;
+    for (name, outcome) in &checks {
+        match outcome {
+            Ok(detail) => println!("[ OK ] {}: {}", name, detail),
+            Err(detail) => {
+                failed += 1;
+                println!("[FAIL] {}: {}", name, detail);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(VisiogenError::Other(format!(
+            "{} of {} doctor check(s) failed",
+            failed,
+            checks.len()
+        )));
+    }
     Ok(())
 }
 
-fn apply_kmer_filters(gene_kmers: Vec<GeneKmers>, kmer_options: &KmerOptions) -> Vec<GeneKmers> {
+#[cfg(feature = "index")]
+fn index_self_test(args: &Args) -> std::result::Result<String, String> {
+    let dir = args.tmpdir.clone().unwrap_or_else(|| ".".to_string());
+    index::round_trip_self_test(std::path::Path::new(&dir))
+        .map(|_| format!("wrote and re-read a test index in {}", dir))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "index"))]
+fn index_self_test(_args: &Args) -> std::result::Result<String, String> {
+    Err("built without the `index` feature; build and off-target screening are unavailable (needs nightly Rust)".to_string())
+}
+
+/// The k-mer options of every target: the global ones, with any
+/// `--gene_options` overrides (each validated like the flags).
+fn gene_options(args: &Args) -> Result<GeneOptions> {
+    let Some(path) = &args.gene_options else {
+        return Ok(GeneOptions::new(&args.kmer_options));
+    };
+    let options = gene_options::read_gene_options(path, &args.kmer_options)?;
+    for (gene, kmer_options) in options.overrides() {
+        validate_kmer_options(kmer_options, &args.off_target).map_err(|e| match e {
+            VisiogenError::InvalidArgument(msg) => {
+                VisiogenError::InvalidArgument(format!("gene {} in {}: {}", gene, path, msg))
+            }
+            other => other,
+        })?;
+    }
+    info!(
+        "Read per-gene k-mer options for {} gene(s) from {}",
+        options.overrides().count(),
+        path
+    );
+    Ok(options)
+}
+
+fn validate_kmer_options(kmer_options: &KmerOptions, off_target: &OffTargetOptions) -> Result<()> {
+    if kmer_options.kmer_size < 1 {
+        return Err(VisiogenError::InvalidArgument(
+            "kmer_size must be at least 1".to_string(),
+        ));
+    }
+    if let Some((min_len, max_len)) = kmer_options.variable_length() {
+        if min_len < 1 || min_len > max_len {
+            return Err(VisiogenError::InvalidArgument(format!(
+                "--kmer_min ({}) must be at least 1 and no more than --kmer_max ({})",
+                min_len, max_len
+            )));
+        }
+    }
+    // Probes are screened by their k-mers, so a shorter one has none to look up
+    #[cfg(feature = "index")]
+    if off_target.screens() && kmer_options.min_probe_len() < index::K {
+        return Err(VisiogenError::InvalidArgument(format!(
+            "probes shorter than the index k ({}) cannot be screened off-target; raise --kmer_size/--kmer_min or pass --no_offtarget",
+            index::K
+        )));
+    }
+    #[cfg(not(feature = "index"))]
+    let _ = off_target;
+    Ok(())
+}
+
+fn apply_kmer_filters(gene_kmers: Vec<GeneKmers>, options: &GeneOptions) -> Vec<GeneKmers> {
+    log_center_base(options.global());
+    for (gene, kmer_options) in options.overrides() {
+        if kmer_options.center_base.is_some() {
+            debug!(gene = gene.as_str(); "Gene {} uses its own --gene_options:", gene);
+            log_center_base(kmer_options);
+        }
+    }
+
     gene_kmers
         .iter()
         .map(|gk| {
-            gk.filter_kmers(
-                kmer_options.center_base,
-                kmer_options.min_gc,
-                kmer_options.max_gc,
-                kmer_options.skip_gc,
-            )
+            let kmer_options = options.for_gene(&gk.gene);
+            gk.log_gc_distribution(kmer_options.min_gc, kmer_options.max_gc);
+            gk.filter_kmers(kmer_options)
+        })
+        .collect()
+}
+
+fn log_center_base(kmer_options: &KmerOptions) {
+    if let Some(base) = kmer_options.center_base {
+        if kmer_options.variable_length().is_some() {
+            info!(
+                "Center base filter: requiring '{}' at 0-based index length/2 of each probe",
+                base
+            );
+        } else {
+            info!(
+                "Center base filter: requiring '{}' at 0-based index {} of each {}-mer",
+                base,
+                Probes::junction_index(kmer_options.kmer_size),
+                kmer_options.kmer_size
+            );
+        }
+    }
+}
+
+/// Drop probes whose genomic span overlaps a known variant site at any of
+/// their locations.
+fn remove_variant_overlaps(gene_kmers: Vec<GeneKmers>, sites: &VariantSites) -> Vec<GeneKmers> {
+    gene_kmers
+        .iter()
+        .map(|gk| {
+            let kept: ProbeSet = gk
+                .kmers
+                .iter()
+                .filter(|probe| {
+                    probe.locations.iter().all(|&loc| {
+                        let (start, end) = gk.genomic_interval(loc, probe.kmer.len());
+                        !sites.overlaps(&gk.seqid, start, end)
+                    })
+                })
+                .cloned()
+                .collect();
+            if kept.len() < gk.kmers.len() {
+                info!(
+                    gene = gk.gene.as_str();
+                    "Gene {}: dropped {} probe(s) overlapping variant sites",
+                    gk.gene,
+                    gk.kmers.len() - kept.len()
+                );
+            }
+            gk.with_kmers(kept)
         })
         .collect()
 }
 
-fn select_best_probes(gene_kmers: Vec<GeneKmers>, n_count: u16) -> Vec<GeneKmers> {
+/// Keep the probes also found in enough `--pan_targets` FASTAs, when any are
+/// given.
+fn screen_pan_targets(gene_kmers: Vec<GeneKmers>, args: &Args) -> Result<Vec<GeneKmers>> {
+    if args.pan_targets.is_empty() {
+        return Ok(gene_kmers);
+    }
+    pan::keep_conserved(
+        gene_kmers,
+        &args.pan_targets,
+        args.min_pan_fraction,
+        args.fasta_case,
+    )
+}
+
+/// Drop probes whose genomic span, at any of their locations, comes within
+/// `buffer` bp of either end of its contig. Targets on contigs missing from
+/// the reference are left as they are.
+fn remove_contig_edge_probes(
+    gene_kmers: Vec<GeneKmers>,
+    reference: &HashMap<String, String>,
+    buffer: usize,
+) -> Vec<GeneKmers> {
     gene_kmers
         .iter()
-        .map(|gk| gk.best_probes(n_count))
+        .map(|gk| {
+            let Some(contig_len) = reference.get(&gk.seqid).map(String::len) else {
+                debug!(
+                    gene = gk.gene.as_str();
+                    "Gene {}: contig {} not in the reference, --contig_edge_buffer not applied",
+                    gk.gene, gk.seqid
+                );
+                return gk.clone();
+            };
+            let kept: ProbeSet = gk
+                .kmers
+                .iter()
+                .filter(|probe| {
+                    probe.locations.iter().all(|&loc| {
+                        let (start, end) = gk.genomic_interval(loc, probe.kmer.len());
+                        start >= buffer && end + buffer <= contig_len
+                    })
+                })
+                .cloned()
+                .collect();
+            if kept.len() < gk.kmers.len() {
+                info!(
+                    gene = gk.gene.as_str();
+                    "Gene {}: dropped {} probe(s) within {} bp of a contig end",
+                    gk.gene,
+                    gk.kmers.len() - kept.len(),
+                    buffer
+                );
+            }
+            gk.with_kmers(kept)
+        })
         .collect()
 }
 
+/// Report probes that occur in more than one target's probe set, which
+/// cannot tell those targets apart; with `drop_shared` they are removed.
+fn report_shared_probes(gene_kmers: Vec<GeneKmers>, drop_shared: bool) -> Vec<GeneKmers> {
+    let mut shared: Vec<(String, Vec<usize>)> = kmer_gene_index(&gene_kmers)
+        .into_iter()
+        .filter(|(_, genes)| genes.len() > 1)
+        .collect();
+    if shared.is_empty() {
+        return gene_kmers;
+    }
+    shared.sort();
+
+    for (kmer, genes) in &shared {
+        let names: Vec<&str> = genes.iter().map(|&i| gene_kmers[i].gene.as_str()).collect();
+        info!(
+            kmer = kmer.as_str();
+            "Probe {} is shared by {} targets: {}",
+            kmer,
+            genes.len(),
+            names.join(", ")
+        );
+    }
+    warn!(
+        "{} probe(s) are shared between targets and cannot distinguish them{}",
+        shared.len(),
+        if drop_shared {
+            "; dropping them"
+        } else {
+            "; see the log, or use --drop_shared"
+        }
+    );
+    if !drop_shared {
+        return gene_kmers;
+    }
+
+    let shared: HashSet<String> = shared.into_iter().map(|(kmer, _)| kmer).collect();
+    gene_kmers
+        .iter()
+        .map(|gk| {
+            let kept: ProbeSet = gk
+                .kmers
+                .iter()
+                .filter(|probe| !shared.contains(&probe.kmer))
+                .cloned()
+                .collect();
+            gk.with_kmers(kept)
+        })
+        .collect()
+}
+
+/// Keep the end-to-end windows of each gene that pass the filters, reporting
+/// every window that failed so the user can see where coverage breaks.
+fn select_contiguous_tiles(gene_kmers: Vec<GeneKmers>, options: &GeneOptions) -> Vec<GeneKmers> {
+    let tiled: Vec<GeneKmers> = gene_kmers
+        .iter()
+        .map(|gk| gk.contiguous_tiles(options.for_gene(&gk.gene).kmer_size))
+        .collect();
+    let passing = apply_kmer_filters(tiled.clone(), options);
+
+    for (all, kept) in tiled.iter().zip(&passing) {
+        let kept_kmers: HashSet<&str> = kept.kmers.iter().map(|p| p.kmer.as_str()).collect();
+        for probe in all
+            .kmers
+            .iter()
+            .filter(|p| !kept_kmers.contains(p.kmer.as_str()))
+        {
+            for &loc in &probe.locations {
+                warn!(
+                    gene = all.gene.as_str();
+                    "Gene {}: contiguous window at offset {} failed filters; coverage breaks here",
+                    all.gene,
+                    all.relative_offset(loc)
+                );
+            }
+        }
+        info!(
+            gene = all.gene.as_str();
+            "Gene {}: {} of {} contiguous windows passed filters",
+            all.gene,
+            kept.kmers.len(),
+            all.kmers.len()
+        );
+    }
+
+    passing
+}
+
+/// Keep the best-scoring probes of each gene, at most `--keep_n_per_window`
+/// per window when set, and at most `--probe_count` overall.
+fn select_best_probes(
+    mut gene_kmers: Vec<GeneKmers>,
+    args: &Args,
+    options: &GeneOptions,
+    weights: &ScoreWeights,
+) -> Vec<GeneKmers> {
+    if *weights != ScoreWeights::default() {
+        info!("Scoring probes with weights {:?}", weights);
+        for probe in gene_kmers.iter_mut().flat_map(|gk| gk.kmers.iter_mut()) {
+            probe.rescore(weights);
+        }
+    }
+
+    let select = |gk: &GeneKmers| match args.tm_target {
+        Some(tm_target) => gk.closest_tm_probes(tm_target, args.n_count),
+        None => gk.best_probes(args.n_count),
+    };
+    let selected: Vec<GeneKmers> = gene_kmers
+        .iter()
+        .map(|gk| match args.keep_n_per_window {
+            0 => select(gk),
+            n => select(&gk.best_per_window(options.for_gene(&gk.gene).min_probe_len(), n)),
+        })
+        .collect();
+    log_tm_spread(&selected, args.tm_target);
+    selected
+}
+
+/// Log the mean, standard deviation and range of the selected probes' Tm.
+fn log_tm_spread(gene_kmers: &[GeneKmers], tm_target: Option<f64>) {
+    let tms: Vec<f64> = gene_kmers
+        .iter()
+        .flat_map(|gk| gk.kmers.iter().map(|p| melting_temperature(&p.kmer)))
+        .collect();
+    if tms.is_empty() {
+        return;
+    }
+    let mean = tms.iter().sum::<f64>() / tms.len() as f64;
+    let sd = (tms.iter().map(|tm| (tm - mean).powi(2)).sum::<f64>() / tms.len() as f64).sqrt();
+    let (min, max) = tms
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &tm| {
+            (lo.min(tm), hi.max(tm))
+        });
+    info!(
+        "Selected probe Tm: mean {:.1} C, sd {:.2} C, range {:.1}-{:.1} C{}",
+        mean,
+        sd,
+        min,
+        max,
+        tm_target.map_or(String::new(), |t| format!(" (target {:.1} C)", t))
+    );
+}
+
 fn main() {
     let args = parse_args();
-    logging::set_up_logging();
+    logging::set_up_logging(args.log_format);
 
     if let Err(e) = run(args) {
         eprintln!("Error: {}", e);
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 }
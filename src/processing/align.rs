@@ -0,0 +1,198 @@
+use bio::alignment::pairwise::{Aligner, MatchFunc};
+use bio::alignment::AlignmentOperation;
+use log::*;
+use needletail::parse_fastx_file;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::core::seq::reverse_complement;
+
+const GAP_OPEN: i32 = -5;
+const GAP_EXTEND: i32 = -1;
+
+/// Locate the FASTA an index was built from. Indexes are written next to
+/// their source with the extension swapped to `.cbl`; FASTAs indexed in
+/// memory are their own source.
+pub fn source_fasta_for_index(index_path: &Path) -> Option<PathBuf> {
+    let is_fasta = index_path
+        .extension()
+        .is_some_and(|ext| ext == "fasta" || ext == "fa");
+    if is_fasta {
+        return Some(index_path.to_path_buf());
+    }
+    ["fasta", "fa"]
+        .iter()
+        .map(|ext| index_path.with_extension(ext))
+        .find(|p| p.is_file())
+}
+
+/// Percent identity of `probe` aligned semi-globally (whole probe, local in
+/// target) against `window`.
+fn percent_identity<F: MatchFunc>(aligner: &mut Aligner<F>, probe: &[u8], window: &[u8]) -> f64 {
+    let alignment = aligner.semiglobal(probe, window);
+    let matches = alignment
+        .operations
+        .iter()
+        .filter(|op| **op == AlignmentOperation::Match)
+        .count();
+
+    matches as f64 * 100.0 / probe.len() as f64
+}
+
+/// Seed length of the alignment screen: short enough that a probe with a
+/// few scattered mismatches still has exact seeds to extend.
+pub const SEED_LEN: usize = 12;
+
+/// Seed-and-extend of every query against one `target`: each exact
+/// `seed_len` window shared by a query and the target places the query on a
+/// diagonal, and every distinct placement is extended by aligning the whole
+/// query against the surrounding region. Updates `best` (indexed like
+/// `queries`) with the highest identity found per query.
+fn extend_seeds_in_target(
+    queries: &[&[u8]],
+    target: &[u8],
+    seed_len: usize,
+    best: &mut [Option<f64>],
+) {
+    let mut seeds: HashMap<&[u8], Vec<(usize, usize)>> = HashMap::new();
+    for (q, query) in queries.iter().enumerate() {
+        if query.len() < seed_len {
+            continue;
+        }
+        for offset in 0..=query.len() - seed_len {
+            seeds
+                .entry(&query[offset..offset + seed_len])
+                .or_default()
+                .push((q, offset));
+        }
+    }
+    if seeds.is_empty() || target.len() < seed_len {
+        return;
+    }
+
+    let score = |a: u8, b: u8| if a == b { 1i32 } else { -1i32 };
+    let longest = queries.iter().map(|q| q.len()).max().unwrap_or(0);
+    let mut aligner = Aligner::with_capacity(longest, longest * 2, GAP_OPEN, GAP_EXTEND, &score);
+    let mut placed: HashSet<(usize, usize)> = HashSet::new();
+
+    for (pos, window) in target.windows(seed_len).enumerate() {
+        let Some(hits) = seeds.get(window) else {
+            continue;
+        };
+        for &(q, offset) in hits {
+            let query = queries[q];
+            let Some(diagonal) = pos.checked_sub(offset) else {
+                continue;
+            };
+            if best[q] == Some(100.0) || !placed.insert((q, diagonal)) {
+                continue;
+            }
+            // Room for a few indels either side of the seeded placement
+            let slack = query.len() / 4;
+            let start = diagonal.saturating_sub(slack);
+            let end = (diagonal + query.len() + slack).min(target.len());
+            let identity = percent_identity(&mut aligner, query, &target[start..end]);
+            best[q] = Some(best[q].map_or(identity, |b| b.max(identity)));
+        }
+    }
+}
+
+/// For each probe, compute the best percent identity (either orientation)
+/// against any record of the FASTA at `fasta_path`. Probes without a seeded
+/// placement are left out.
+pub fn best_identities_in_fasta(
+    fasta_path: &Path,
+    probes: &[String],
+    seed_len: usize,
+) -> Result<HashMap<String, f64>, Box<dyn std::error::Error>> {
+    // Forward and reverse complement of every probe, in that order
+    let queries: Vec<Vec<u8>> = probes
+        .iter()
+        .flat_map(|p| {
+            let fwd = p.to_ascii_uppercase();
+            let rev = reverse_complement(&fwd);
+            [fwd.into_bytes(), rev.into_bytes()]
+        })
+        .collect();
+    let query_refs: Vec<&[u8]> = queries.iter().map(Vec::as_slice).collect();
+
+    let mut best: Vec<Option<f64>> = vec![None; queries.len()];
+    let mut reader = parse_fastx_file(fasta_path)?;
+    while let Some(record) = reader.next() {
+        let seqrec = record?;
+        let target = seqrec.seq().to_ascii_uppercase();
+        extend_seeds_in_target(&query_refs, &target, seed_len, &mut best);
+    }
+
+    let identities: HashMap<String, f64> = probes
+        .iter()
+        .zip(best.chunks(2))
+        .filter_map(|(probe, both)| {
+            let identity = both.iter().flatten().copied().reduce(f64::max)?;
+            Some((probe.clone(), identity))
+        })
+        .collect();
+
+    debug!(
+        "Aligned {} probe(s) against {:?}; {} had a seeded placement",
+        probes.len(),
+        fasta_path,
+        identities.len()
+    );
+
+    Ok(identities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn best_identity(probe: &str, target: &str) -> Option<f64> {
+        let mut best = vec![None];
+        extend_seeds_in_target(&[probe.as_bytes()], target.as_bytes(), SEED_LEN, &mut best);
+        best[0]
+    }
+
+    const PROBE: &str = "ACGTTGCATGCCTAGGATCCAGTTACGGATCAAGCTTGACCTGTAGCATG";
+
+    #[test]
+    fn exact_placement_is_full_identity() {
+        let target = format!("TTTTTTTTTT{}GGGGGGGGGG", PROBE);
+        assert_eq!(best_identity(PROBE, &target), Some(100.0));
+    }
+
+    #[test]
+    fn mismatched_off_target_is_found() {
+        // Three scattered substitutions leave no exact 49-mer, but 12-mer seeds
+        let mut off_target = PROBE.as_bytes().to_vec();
+        for pos in [12, 25, 38] {
+            off_target[pos] = if off_target[pos] == b'A' { b'C' } else { b'A' };
+        }
+        let target = format!(
+            "CCCCCCCCCC{}AAAAAAAAAA",
+            String::from_utf8(off_target).unwrap()
+        );
+        assert_eq!(best_identity(PROBE, &target), Some(94.0));
+    }
+
+    #[test]
+    fn best_of_several_placements_is_kept() {
+        let mut worse = PROBE.as_bytes().to_vec();
+        for pos in [5, 15, 30, 45] {
+            worse[pos] = if worse[pos] == b'G' { b'T' } else { b'G' };
+        }
+        let mut better = PROBE.as_bytes().to_vec();
+        better[20] = if better[20] == b'G' { b'T' } else { b'G' };
+        let target = format!(
+            "{}TTTTTTTTTTTTTTTTTTTT{}",
+            String::from_utf8(worse).unwrap(),
+            String::from_utf8(better).unwrap()
+        );
+        assert_eq!(best_identity(PROBE, &target), Some(98.0));
+    }
+
+    #[test]
+    fn unrelated_target_has_no_placement() {
+        assert_eq!(best_identity(PROBE, &"AT".repeat(100)), None);
+    }
+}
@@ -0,0 +1,86 @@
+use bio_types::strand::Strand;
+use std::collections::HashSet;
+use std::io::BufRead;
+
+use crate::error::{Result, VisiogenError};
+use crate::io::utils::{input_name, open_input};
+use crate::processing::gff::GeneIndex;
+
+/// One BED target: (name, seqid, start, end, strand) with the 0-based
+/// half-open BED interval converted to GFF-style 1-based inclusive bounds.
+pub type BedTarget = (String, String, u64, u64, Strand);
+
+/// Read every target of a BED file, or of standard input when `bed_path` is
+/// `-`. Three-column intervals, as most interval tools emit, are named
+/// `chrom:start-end` in BED coordinates.
+pub fn read_bed_targets(bed_path: &str) -> Result<Vec<BedTarget>> {
+    let source = input_name(bed_path);
+    let reader = open_input(bed_path)
+        .map_err(|e| VisiogenError::BedParseError(format!("Failed to open {}: {}", source, e)))?;
+
+    let mut targets = Vec::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let malformed = |what: &str| {
+            VisiogenError::BedParseError(format!("{} line {}: {}", source, line_no + 1, what))
+        };
+        if fields.len() < 3 {
+            return Err(malformed("expected chrom, start and end columns"));
+        }
+        let start: u64 = fields[1]
+            .trim()
+            .parse()
+            .map_err(|_| malformed("start is not a number"))?;
+        let end: u64 = fields[2]
+            .trim()
+            .parse()
+            .map_err(|_| malformed("end is not a number"))?;
+        if end <= start {
+            return Err(malformed("end must be greater than start"));
+        }
+        let strand = match fields.get(5).map(|s| s.trim()) {
+            Some("-") => Strand::Reverse,
+            _ => Strand::Forward,
+        };
+
+        let name = match fields.get(3) {
+            Some(name) => name.trim().to_string(),
+            None => format!("{}:{}-{}", fields[0], start, end),
+        };
+
+        // BED start is 0-based, end exclusive: the same bases as GFF start+1..=end
+        targets.push((name, fields[0].to_string(), start + 1, end, strand));
+    }
+    Ok(targets)
+}
+
+/// Index BED targets by name, in the same shape as the GFF gene index. The
+/// first line with a given name wins.
+pub fn build_target_index(targets: &[BedTarget]) -> GeneIndex {
+    let mut index = GeneIndex::new();
+    for (name, seqid, start, end, strand) in targets {
+        index
+            .entry(name.clone())
+            .or_insert((seqid.clone(), *start, *end, *strand));
+    }
+    index
+}
+
+/// Names of every BED target, in file order.
+pub fn target_names(targets: &[BedTarget]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    targets
+        .iter()
+        .map(|(name, ..)| name.clone())
+        .filter(|name| seen.insert(name.clone()))
+        .collect()
+}
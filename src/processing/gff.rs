@@ -1,31 +1,750 @@
 use bio::io::gff;
 use bio_types::strand::Strand;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::*;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Cursor};
 use std::path::Path;
+use std::sync::Mutex;
 
+use crate::cli::KmerOptions;
+use crate::core::probes::{GeneKmers, Probes};
+use crate::core::seq::{filter_hashmap, reverse_complement};
+use crate::core::summary::{SkipReason, SkippedTarget};
 use crate::error::{Result, VisiogenError};
+use crate::io::fasta;
+use crate::io::gene_options::GeneOptions;
+use crate::io::utils::Deadline;
 
-pub fn coords_from_gene_name(
-    gff_path: &String,
-    gene: &String,
-) -> Result<Option<(u64, u64, Strand)>> {
-    let path = Path::new(gff_path);
-    let file = File::open(path).map_err(|e| VisiogenError::IoError(e))?;
-    let reader = BufReader::new(file);
-    let mut gff_reader = gff::Reader::new(reader, gff::GffType::GFF3);
+/// Directive after which a GFF3 file embeds its sequences as FASTA.
+const FASTA_DIRECTIVE: &str = "##FASTA";
+
+/// Split a GFF3 file into its annotation text and, when present, the FASTA
+/// text following a `##FASTA` directive, reading it line by line.
+pub fn read_gff_sections(gff_path: &str) -> Result<(String, Option<String>)> {
+    let file = File::open(Path::new(gff_path)).map_err(VisiogenError::IoError)?;
+    let mut annotation = String::new();
+    let mut fasta: Option<String> = None;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(VisiogenError::IoError)?;
+        let section = match &mut fasta {
+            Some(fasta) => fasta,
+            None if line.trim_end() == FASTA_DIRECTIVE => {
+                fasta = Some(String::new());
+                continue;
+            }
+            None => &mut annotation,
+        };
+        section.push_str(&line);
+        section.push('\n');
+    }
+    Ok((annotation, fasta))
+}
+
+/// Reader over the annotation lines only, so an embedded FASTA section is
+/// never fed to the GFF parser.
+fn open_gff(gff_path: &str) -> Result<gff::Reader<Cursor<String>>> {
+    let (annotation, _) = read_gff_sections(gff_path)?;
+    Ok(gff::Reader::new(
+        Cursor::new(annotation),
+        gff::GffType::GFF3,
+    ))
+}
+
+/// Sequences embedded after `##FASTA` in the GFF, if it has any.
+pub fn embedded_fasta(gff_path: &str) -> Result<Option<HashMap<String, String>>> {
+    match read_gff_sections(gff_path)? {
+        (_, Some(fasta)) => Ok(Some(fasta::parse_fasta_text(fasta, gff_path)?)),
+        (_, None) => Ok(None),
+    }
+}
+
+/// Longest list of names quoted in one assembly-mismatch message.
+const MAX_LISTED: usize = 5;
+
+/// Signs that the GFF `annotation` (as split off by `read_gff_sections`)
+/// and `reference` describe different assemblies: feature seqids with no
+/// reference record, `##sequence-region` lengths that differ from the
+/// record's, and features running past the end of their record. Each
+/// problem is one message; an empty list means they agree.
+pub fn assembly_mismatches(
+    annotation: &str,
+    reference: &HashMap<String, String>,
+) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    for line in annotation.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let ["##sequence-region", seqid, start, end, ..] = fields[..] {
+            let (Ok(start), Ok(end)) = (start.parse::<u64>(), end.parse::<u64>()) else {
+                continue;
+            };
+            if let Some(sequence) = reference.get(seqid) {
+                let declared = end.saturating_sub(start) + 1;
+                if declared != sequence.len() as u64 {
+                    problems.push(format!(
+                        "##sequence-region {} declares {} bp but the reference record is {} bp",
+                        seqid,
+                        declared,
+                        sequence.len()
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut missing: Vec<String> = Vec::new();
+    let mut overrun: Vec<String> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut reader = gff::Reader::new(annotation.as_bytes(), gff::GffType::GFF3);
+    for record in reader.records() {
+        let rec = record.map_err(|e| VisiogenError::IoError(e.into()))?;
+        let seqid = rec.seqname();
+        match reference.get(seqid) {
+            None => {
+                if seen.insert(seqid.to_string()) {
+                    missing.push(seqid.to_string());
+                }
+            }
+            Some(sequence) if *rec.end() > sequence.len() as u64 => {
+                overrun.push(format!("{}:{}-{}", seqid, rec.start(), rec.end()))
+            }
+            Some(_) => (),
+        }
+    }
 
+    let listed = |names: &[String]| {
+        let mut list = names[..names.len().min(MAX_LISTED)].join(", ");
+        if names.len() > MAX_LISTED {
+            list.push_str(", ...");
+        }
+        list
+    };
+    if !missing.is_empty() {
+        problems.push(format!(
+            "{} GFF seqid(s) have no reference record: {}",
+            missing.len(),
+            listed(&missing)
+        ));
+    }
+    if !overrun.is_empty() {
+        problems.push(format!(
+            "{} feature(s) end past their reference record: {}",
+            overrun.len(),
+            listed(&overrun)
+        ));
+    }
+    Ok(problems)
+}
+
+/// Error for a GFF without a single feature line (empty, or comments only),
+/// which would otherwise report every requested gene as missing.
+fn no_features(gff_path: &str) -> VisiogenError {
+    VisiogenError::GffParseError(format!(
+        "{} contains no features — is the file valid GFF3?",
+        gff_path
+    ))
+}
+
+/// Gene name -> (seqid, start, end, strand), GFF 1-based inclusive coordinates.
+pub type GeneIndex = HashMap<String, (String, u64, u64, Strand)>;
+
+/// Parse the GFF once, indexing every `feature_type` feature by its `Name`
+/// attribute. The first feature with a given name wins.
+pub fn build_gene_index(gff_path: &str, feature_type: &str) -> Result<GeneIndex> {
+    let mut gff_reader = open_gff(gff_path)?;
+    let mut index = GeneIndex::new();
+
+    let mut records = 0;
     for record in gff_reader.records() {
         let rec = record.map_err(|e| VisiogenError::IoError(e.into()))?;
-        if let Some(attributes) = rec.attributes().get("Name") {
-            if attributes == gene {
-                return Ok(Some((
+        records += 1;
+        if rec.feature_type() != feature_type {
+            continue;
+        }
+        if let Some(name) = rec.attributes().get("Name") {
+            index.entry(name.clone()).or_insert_with(|| {
+                (
+                    rec.seqname().to_string(),
                     *rec.start(),
                     *rec.end(),
                     rec.strand().unwrap_or(Strand::Forward),
-                )));
+                )
+            });
+        }
+    }
+    if records == 0 {
+        return Err(no_features(gff_path));
+    }
+    Ok(index)
+}
+
+/// The region strictly between two indexed genes, named `first-second`, for
+/// `--between`. Both genes must sit on the same contig and strand without
+/// overlapping; they may be given in either order.
+pub fn between_region(
+    index: &GeneIndex,
+    first: &str,
+    second: &str,
+) -> Result<(String, (String, u64, u64, Strand))> {
+    let lookup = |name: &str| {
+        index.get(name).ok_or_else(|| {
+            VisiogenError::InvalidArgument(format!("--between: gene {} not found", name))
+        })
+    };
+    let (a, b) = (lookup(first)?, lookup(second)?);
+
+    if a.0 != b.0 {
+        return Err(VisiogenError::InvalidArgument(format!(
+            "--between: {} is on {} but {} is on {}",
+            first, a.0, second, b.0
+        )));
+    }
+    if a.3 != b.3 {
+        return Err(VisiogenError::InvalidArgument(format!(
+            "--between: {} and {} are on opposite strands",
+            first, second
+        )));
+    }
+    if a.1 <= b.2 && b.1 <= a.2 {
+        return Err(VisiogenError::InvalidArgument(format!(
+            "--between: {} ({}-{}) and {} ({}-{}) overlap",
+            first, a.1, a.2, second, b.1, b.2
+        )));
+    }
+
+    let (left, right) = if a.1 < b.1 { (a, b) } else { (b, a) };
+    let (start, end) = (left.2 + 1, right.1 - 1);
+    if start > end {
+        return Err(VisiogenError::InvalidArgument(format!(
+            "--between: {} and {} are adjacent, with no bases between them",
+            first, second
+        )));
+    }
+
+    let name = format!("{}-{}", first, second);
+    info!(
+        "Region between {} and {}: {}:{}-{} ({} bp)",
+        first,
+        second,
+        a.0,
+        start,
+        end,
+        end - start + 1
+    );
+    Ok((name, (a.0.clone(), start, end, a.3)))
+}
+
+/// Exons of one transcript on `seqid`, sorted by position, as 1-based
+/// inclusive (start, end) pairs.
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    pub id: String,
+    pub seqid: String,
+    pub strand: Strand,
+    pub exons: Vec<(u64, u64)>,
+}
+
+/// Gene name -> the first of its transcripts that has exons.
+pub type ExonIndex = HashMap<String, Transcript>;
+
+/// Parse the GFF once, linking `exon` features to genes of `feature_type`
+/// through their `Parent` attributes: gene -> transcript (e.g. mRNA) -> exon,
+/// or exons parented by the gene directly.
+pub fn build_exon_index(gff_path: &str, feature_type: &str) -> Result<ExonIndex> {
+    let mut gff_reader = open_gff(gff_path)?;
+    let mut genes: Vec<(String, String)> = Vec::new();
+    let mut children: HashMap<String, Vec<String>> = HashMap::new();
+    let mut exons: HashMap<String, Vec<(String, u64, u64, Strand)>> = HashMap::new();
+
+    let mut records = 0;
+    for record in gff_reader.records() {
+        let rec = record.map_err(|e| VisiogenError::IoError(e.into()))?;
+        records += 1;
+        let attributes = rec.attributes();
+        let parents = attributes
+            .get("Parent")
+            .map(|p| p.split(',').map(str::to_string).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        if rec.feature_type() == "exon" {
+            for parent in parents {
+                exons.entry(parent).or_default().push((
+                    rec.seqname().to_string(),
+                    *rec.start(),
+                    *rec.end(),
+                    rec.strand().unwrap_or(Strand::Forward),
+                ));
+            }
+            continue;
+        }
+        let Some(id) = attributes.get("ID") else {
+            continue;
+        };
+        if rec.feature_type() == feature_type {
+            if let Some(name) = attributes.get("Name") {
+                genes.push((name.clone(), id.clone()));
+            }
+        }
+        for parent in parents {
+            children.entry(parent).or_default().push(id.clone());
+        }
+    }
+    if records == 0 {
+        return Err(no_features(gff_path));
+    }
+
+    let mut index = ExonIndex::new();
+    for (name, gene_id) in genes {
+        if index.contains_key(&name) {
+            continue;
+        }
+        let transcript_id = children
+            .get(&gene_id)
+            .into_iter()
+            .flatten()
+            .find(|child| exons.contains_key(*child))
+            .unwrap_or(&gene_id);
+        let Some(transcript_exons) = exons.get(transcript_id) else {
+            continue;
+        };
+        let (seqid, _, _, strand) = transcript_exons[0].clone();
+        let mut intervals: Vec<(u64, u64)> = transcript_exons
+            .iter()
+            .map(|&(_, start, end, _)| (start, end))
+            .collect();
+        intervals.sort_unstable();
+        index.insert(
+            name,
+            Transcript {
+                id: transcript_id.clone(),
+                seqid,
+                strand,
+                exons: intervals,
+            },
+        );
+    }
+    Ok(index)
+}
+
+/// Splice every requested gene's transcript from the reference and tile it,
+/// keeping only probes that span an exon-exon junction (tagged with
+/// `splice_junction`). Probe coordinates are positions in the spliced
+/// transcript, which is returned by ID so it can serve as a reference. Like
+/// `generate_gene_kmers`, no more genes are started once `deadline` expires;
+/// those left out are returned last, in order.
+pub fn generate_junction_kmers(
+    exon_index: &ExonIndex,
+    reference: &HashMap<String, String>,
+    genes: &[String],
+    options: &GeneOptions,
+    deadline: Deadline,
+) -> (Vec<GeneKmers>, HashMap<String, String>, Vec<String>) {
+    let progress = gene_progress(genes.len());
+    let unprocessed = Mutex::new(HashSet::new());
+    let (gene_kmers, transcripts) = genes
+        .par_iter()
+        .filter_map(|gene| {
+            if deadline.expired() {
+                unprocessed.lock().unwrap().insert(gene);
+                progress.inc(1);
+                return None;
+            }
+            let result = junction_kmers_for(exon_index, reference, gene, options.for_gene(gene));
+            progress.inc(1);
+            result
+        })
+        .map(|(gk, transcript)| {
+            let id = gk.seqid.clone();
+            (gk, (id, transcript))
+        })
+        .unzip();
+    progress.finish();
+
+    let unprocessed = in_gene_order(genes, &unprocessed.into_inner().unwrap());
+    (gene_kmers, transcripts, unprocessed)
+}
+
+/// Progress bar over `len` genes.
+fn gene_progress(len: usize) -> ProgressBar {
+    let progress = ProgressBar::new(len as u64);
+    progress.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.green/blue}] {pos}/{len} genes ({percent}%, ETA {eta})")
+        .unwrap()
+        .progress_chars("##-"));
+    progress
+}
+
+/// The `genes` in `subset`, in the order requested.
+fn in_gene_order(genes: &[String], subset: &HashSet<&String>) -> Vec<String> {
+    genes
+        .iter()
+        .filter(|gene| subset.contains(gene))
+        .cloned()
+        .collect()
+}
+
+fn junction_kmers_for(
+    exon_index: &ExonIndex,
+    reference: &HashMap<String, String>,
+    gene: &String,
+    kmer_options: &KmerOptions,
+) -> Option<(GeneKmers, String)> {
+    let Some(transcript) = exon_index.get(gene) else {
+        warn!(gene = gene.as_str(); "Gene {} has no exon features in the GFF", gene);
+        return None;
+    };
+    if transcript.exons.len() < 2 {
+        warn!(
+            gene = gene.as_str();
+            "Gene {} (transcript {}) has a single exon; no junctions to span",
+            gene, transcript.id
+        );
+        return None;
+    }
+    let Some(contig) = reference.get(&transcript.seqid) else {
+        warn!(
+            gene = gene.as_str();
+            "Sequence '{}' for gene {} not found in reference FASTA",
+            transcript.seqid, gene
+        );
+        return None;
+    };
+
+    let mut spliced = String::new();
+    let mut boundaries = Vec::new();
+    for &(start, end) in &transcript.exons {
+        let (slice_start, slice_end) = ((start as usize).saturating_sub(1), end as usize);
+        if start == 0 || slice_end > contig.len() || slice_start >= slice_end {
+            warn!(
+                gene = gene.as_str();
+                "Exon {}:{}-{} of gene {} lies outside its reference sequence",
+                transcript.seqid, start, end, gene
+            );
+            return None;
+        }
+        if !spliced.is_empty() {
+            boundaries.push(spliced.len());
+        }
+        spliced.push_str(&contig[slice_start..slice_end]);
+    }
+    // Read the transcript 5' to 3'
+    if transcript.strand == Strand::Reverse {
+        spliced = reverse_complement(&spliced);
+        boundaries = boundaries.iter().rev().map(|b| spliced.len() - b).collect();
+    }
+
+    let mut kmers = Probes::tile(&spliced, kmer_options, 0);
+    kmers.retain_mut(|probe| {
+        let len = probe.kmer.len();
+        probe.splice_junction = probe.locations.iter().find_map(|&loc| {
+            boundaries
+                .iter()
+                .find(|&&b| loc < b && b < loc + len)
+                .map(|&b| b + 1)
+        });
+        probe.splice_junction.is_some()
+    });
+    info!(
+        gene = gene.as_str();
+        "Gene {} (transcript {}, {} exons, {} bp spliced) produced {} junction-spanning kmers",
+        gene,
+        transcript.id,
+        transcript.exons.len(),
+        spliced.len(),
+        kmers.len()
+    );
+
+    let gk = GeneKmers {
+        gene: gene.clone(),
+        seqid: transcript.id.clone(),
+        start: 1,
+        end: spliced.len() as u64,
+        kmers,
+        strand: "+".to_string(),
+        kmer_hits: HashMap::new(),
+    };
+    Some((gk, spliced))
+}
+
+/// Names of every feature of `feature_type` in the GFF, in file order.
+pub fn feature_names(gff_path: &str, feature_type: &str) -> Result<Vec<String>> {
+    let mut gff_reader = open_gff(gff_path)?;
+    let mut seen = HashSet::new();
+    let mut names = Vec::new();
+
+    let mut records = 0;
+    for record in gff_reader.records() {
+        let rec = record.map_err(|e| VisiogenError::IoError(e.into()))?;
+        records += 1;
+        if rec.feature_type() != feature_type {
+            continue;
+        }
+        if let Some(name) = rec.attributes().get("Name") {
+            if seen.insert(name.clone()) {
+                names.push(name.clone());
+            }
+        }
+    }
+    if records == 0 {
+        return Err(no_features(gff_path));
+    }
+    Ok(names)
+}
+
+/// Tile every requested gene from the reference, in parallel. Genes missing
+/// from the GFF or reference, or shorter than the kmer size (unless
+/// `--allow_short`), are skipped with a warning and returned with the reason.
+/// Output order follows `genes`. Once `deadline` expires no more genes are
+/// started; those left out are returned last, in order.
+pub fn generate_gene_kmers(
+    gene_index: &GeneIndex,
+    reference: &HashMap<String, String>,
+    genes: &[String],
+    options: &GeneOptions,
+    deadline: Deadline,
+) -> Result<(Vec<GeneKmers>, Vec<SkippedTarget>, Vec<String>)> {
+    let progress = gene_progress(genes.len());
+    let unprocessed = Mutex::new(HashSet::new());
+    let skipped = Mutex::new(Vec::new());
+    let gene_kmers = genes
+        .par_iter()
+        .map(|gene| {
+            if deadline.expired() {
+                unprocessed.lock().unwrap().insert(gene);
+                progress.inc(1);
+                return Ok(None);
+            }
+            let result = gene_kmers_for(gene_index, reference, gene, options.for_gene(gene));
+            progress.inc(1);
+            match result? {
+                Ok(gene_kmers) => Ok(Some(gene_kmers)),
+                Err(reason) => {
+                    skipped.lock().unwrap().push((gene.clone(), reason));
+                    Ok(None)
+                }
+            }
+        })
+        .filter_map(|result| result.transpose())
+        .collect::<Result<Vec<_>>>()?;
+    progress.finish();
+
+    let skipped = skipped.into_inner().unwrap();
+    let skipped = genes
+        .iter()
+        .filter_map(|gene| skipped.iter().find(|(name, _)| name == gene).cloned())
+        .collect();
+
+    let unprocessed = in_gene_order(genes, &unprocessed.into_inner().unwrap());
+    Ok((gene_kmers, skipped, unprocessed))
+}
+
+fn gene_kmers_for(
+    gene_index: &GeneIndex,
+    reference: &HashMap<String, String>,
+    gene: &String,
+    kmer_options: &KmerOptions,
+) -> Result<std::result::Result<GeneKmers, SkipReason>> {
+    let (kmer_size, edge_trim) = (kmer_options.min_probe_len(), kmer_options.edge_trim);
+    let Some((seqid, start, end, strand)) = gene_index.get(gene).cloned() else {
+        warn!(gene = gene.as_str(); "Gene not found in GFF: {}", gene);
+        return Ok(Err(SkipReason::NotFound));
+    };
+
+    let Some(contig) = reference.get(&seqid) else {
+        warn!(
+            gene = gene.as_str();
+            "Sequence '{}' for gene {} not found in reference FASTA",
+            seqid, gene
+        );
+        return Ok(Err(SkipReason::MissingSequence));
+    };
+
+    // GFF is 1-based inclusive; convert to a 0-based half-open slice
+    let (slice_start, slice_end) = ((start as usize).saturating_sub(1), end as usize);
+    if start == 0 || slice_end > contig.len() || slice_start >= slice_end {
+        warn!(
+            gene = gene.as_str();
+            "Gene {} ({}:{}-{}) lies outside its reference sequence (length {})",
+            gene,
+            seqid,
+            start,
+            end,
+            contig.len()
+        );
+        return Ok(Err(SkipReason::OutsideReference));
+    }
+    let short = slice_end - slice_start < kmer_size;
+    if short && !kmer_options.allow_short {
+        warn!(
+            gene = gene.as_str();
+            "Gene {} is shorter ({} bp) than the kmer size ({}); see --allow_short",
+            gene,
+            slice_end - slice_start,
+            kmer_size
+        );
+        return Ok(Err(SkipReason::TooShort));
+    }
+
+    // A short gene becomes one probe centred on it, padded with reference
+    // context up to the kmer size where the contig allows
+    let (slice_start, slice_end) = if short {
+        let padded_end = (slice_start.saturating_sub((kmer_size - (slice_end - slice_start)) / 2)
+            + kmer_size)
+            .min(contig.len());
+        (padded_end.saturating_sub(kmer_size), padded_end)
+    } else {
+        (slice_start, slice_end)
+    };
+    let tile = |seq: &str, start_offset| {
+        if short {
+            Probes::whole(seq, kmer_options, start_offset)
+        } else {
+            Probes::tile(seq, kmer_options, start_offset)
+        }
+    };
+
+    let region = &contig[slice_start..slice_end];
+    let reverse = strand == Strand::Reverse;
+    let kmers = if reverse {
+        let mut probes = tile(&reverse_complement(region), 0);
+        // Minus-strand locations count down from the gene end
+        for probe in probes.iter_mut() {
+            for loc in probe.locations.iter_mut() {
+                *loc = slice_end - 1 - *loc;
+            }
+        }
+        probes
+    } else {
+        tile(region, slice_start)
+    };
+    // A padded short gene's probe reaches past the gene by design
+    let kmers = if short {
+        kmers
+    } else {
+        let tiled = kmers.len();
+        let kmers = filter_hashmap(kmers, start, end, reverse, kmer_options.allow_outside);
+        if kmers.len() < tiled {
+            debug!(
+                gene = gene.as_str();
+                "Gene {}: dropped {} probe(s) reaching outside {}-{}",
+                gene,
+                tiled - kmers.len(),
+                start,
+                end
+            );
+        }
+        kmers
+    };
+    if kmers.is_empty() {
+        warn!(
+            gene = gene.as_str();
+            "Gene {}: no candidate probes in {} bp; --edge_trim {} may leave no room for a {}-mer",
+            gene,
+            region.len(),
+            edge_trim,
+            kmer_size
+        );
+    }
+
+    info!(
+        gene = gene.as_str();
+        "Gene {} ({}:{}-{}) produced {} candidate kmers",
+        gene,
+        seqid,
+        start,
+        end,
+        kmers.len()
+    );
+
+    Ok(Ok(GeneKmers {
+        gene: gene.clone(),
+        seqid,
+        start,
+        end,
+        kmers,
+        strand: if reverse { "-" } else { "+" }.to_string(),
+        kmer_hits: HashMap::new(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gff_file(text: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+        file
+    }
+
+    fn path_of(file: &tempfile::NamedTempFile) -> &str {
+        file.path().to_str().unwrap()
+    }
+
+    const COMBINED: &str = "##gff-version 3\n\
+        chr1\t.\tgene\t2\t9\t.\t+\t.\tID=geneA;Name=geneA\n\
+        ##FASTA\n\
+        >chr1 first record\n\
+        ACGTACGT\n\
+        ACGT\n\
+        >chr2\n\
+        GGGG\n";
+
+    #[test]
+    fn gff_with_embedded_fasta_splits_at_the_directive() {
+        let file = gff_file(COMBINED);
+        let (annotation, fasta) = read_gff_sections(path_of(&file)).unwrap();
+        assert!(annotation.ends_with("Name=geneA\n"), "{}", annotation);
+        assert!(fasta.unwrap().starts_with(">chr1 first record\n"));
+
+        let index = build_gene_index(path_of(&file), "gene").unwrap();
+        assert_eq!(index["geneA"].1, 2);
+        assert_eq!(index["geneA"].2, 9);
+
+        let sequences = embedded_fasta(path_of(&file)).unwrap().unwrap();
+        assert_eq!(sequences["chr1"], "ACGTACGTACGT");
+        assert_eq!(sequences["chr2"], "GGGG");
+    }
+
+    #[test]
+    fn gff_without_fasta_has_no_embedded_sequences() {
+        let file = gff_file("##gff-version 3\nchr1\t.\tgene\t2\t9\t.\t+\t.\tID=geneA\n");
+        assert!(embedded_fasta(path_of(&file)).unwrap().is_none());
+    }
+
+    #[test]
+    fn assembly_mismatches_reads_the_parsed_annotation() {
+        let file = gff_file(COMBINED);
+        let (annotation, fasta) = read_gff_sections(path_of(&file)).unwrap();
+        let reference = fasta::parse_fasta_text(fasta.unwrap(), "test").unwrap();
+        assert!(assembly_mismatches(&annotation, &reference)
+            .unwrap()
+            .is_empty());
+
+        let short = HashMap::from([("chr1".to_string(), "ACGT".to_string())]);
+        let problems = assembly_mismatches(&annotation, &short).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("chr1:2-9"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn gff_without_features_is_a_distinct_error() {
+        for text in ["", "##gff-version 3\n# no features here\n"] {
+            let file = gff_file(text);
+            for error in [
+                build_gene_index(path_of(&file), "gene").unwrap_err(),
+                feature_names(path_of(&file), "gene").unwrap_err(),
+            ] {
+                assert!(
+                    matches!(&error, VisiogenError::GffParseError(msg) if msg.contains("contains no features")),
+                    "{}",
+                    error
+                );
             }
         }
     }
-    Ok(None)
 }
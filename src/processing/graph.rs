@@ -1,12 +1,39 @@
-use std::collections::HashMap;
+use log::*;
+use std::collections::{HashMap, HashSet};
 use std::io::BufRead;
 
 pub struct Gfa {
+    pub version: GfaVersion,
     pub segments: Vec<Segment>,
     pub links: Vec<Link>,
     pub paths: Vec<GfaPath>,
 }
 
+/// GFA dialect, from the `VN:Z` tag of the `H` line (GFA1 when absent).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GfaVersion {
+    Gfa1,
+    Gfa2,
+}
+
+impl GfaVersion {
+    /// Version for a `VN:Z` value; unknown versions are read as GFA1 with a
+    /// warning.
+    fn from_tag(value: &str) -> Self {
+        match value {
+            "1.0" | "1.1" | "1.2" => GfaVersion::Gfa1,
+            "2.0" => GfaVersion::Gfa2,
+            other => {
+                warn!(
+                    "Unsupported GFA version {}; parsing as GFA1, results may be wrong",
+                    other
+                );
+                GfaVersion::Gfa1
+            }
+        }
+    }
+}
+
 impl Gfa {
     /// Return segment names that appear exactly once in all paths (core)
     pub fn core_segments(&self) -> Vec<String> {
@@ -41,19 +68,277 @@ impl Gfa {
             .collect()
     }
 
+    /// Segment names defined by more than one `S` line. Probes are named after
+    /// segments in graph mode, so duplicates make attribution ambiguous.
+    pub fn duplicate_segment_names(&self) -> Vec<String> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for seg in &self.segments {
+            *counts.entry(seg.name.as_str()).or_insert(0) += 1;
+        }
+
+        let mut duplicates: Vec<String> = counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        duplicates.sort();
+        duplicates
+    }
+
+    /// Segments present in every path but repeated within at least one, so
+    /// neither clearly core nor clearly accessory
+    pub fn ambiguous_segments(&self) -> Vec<String> {
+        let path_count = self.paths.len();
+        let mut paths_containing: HashMap<&str, usize> = HashMap::new();
+        let mut repeated: HashSet<&str> = HashSet::new();
+
+        for path in &self.paths {
+            let mut segment_seen: HashMap<&str, usize> = HashMap::new();
+            for (segment_name, _) in &path.segments {
+                *segment_seen.entry(segment_name.as_str()).or_insert(0) += 1;
+            }
+
+            for (seg, count) in segment_seen {
+                *paths_containing.entry(seg).or_insert(0) += 1;
+                if count > 1 {
+                    repeated.insert(seg);
+                }
+            }
+        }
+
+        let mut ambiguous: Vec<String> = paths_containing
+            .into_iter()
+            .filter(|(seg, count)| *count == path_count && repeated.contains(seg))
+            .map(|(seg, _)| seg.to_string())
+            .collect();
+        ambiguous.sort();
+        ambiguous
+    }
+
+    /// Where each segment sits in every path that visits it, keyed by segment
+    /// name. Offsets add up the full length of every preceding segment (GFA
+    /// overlaps are not subtracted) on top of the path's own start, which is
+    /// the W line's sequence start or 0 for P lines.
+    pub fn segment_path_offsets(&self) -> HashMap<String, Vec<PathPlacement>> {
+        let lengths: HashMap<&str, u64> = self
+            .segments
+            .iter()
+            .map(|seg| (seg.name.as_str(), seg.sequence.len() as u64))
+            .collect();
+        let mut placements: HashMap<String, Vec<PathPlacement>> = HashMap::new();
+
+        for path in &self.paths {
+            let mut offset = path.start;
+            for (segment_name, orient) in &path.segments {
+                let Some(&segment_len) = lengths.get(segment_name.as_str()) else {
+                    debug!(
+                        "Path {} visits segment {} with no S line",
+                        path.name, segment_name
+                    );
+                    continue;
+                };
+                placements
+                    .entry(segment_name.clone())
+                    .or_default()
+                    .push(PathPlacement {
+                        path: path.name.clone(),
+                        start: offset,
+                        segment_len,
+                        reverse: *orient == '-',
+                    });
+                offset += segment_len;
+            }
+        }
+
+        placements
+    }
+
+    /// Segments visited by at least one path but by no more than
+    /// `max_fraction` of them (the accessory genome)
+    pub fn accessory_segments(&self, max_fraction: f64) -> Vec<String> {
+        let path_count = self.paths.len();
+        let mut paths_containing: HashMap<&str, usize> = HashMap::new();
+
+        for path in &self.paths {
+            let visited: HashSet<&str> = path.segments.iter().map(|(s, _)| s.as_str()).collect();
+            for seg in visited {
+                *paths_containing.entry(seg).or_insert(0) += 1;
+            }
+        }
+
+        let mut accessory: Vec<String> = paths_containing
+            .into_iter()
+            .filter(|(_, count)| *count as f64 <= max_fraction * path_count as f64)
+            .map(|(seg, _)| seg.to_string())
+            .collect();
+        accessory.sort();
+        accessory
+    }
+
     /// Return full Segment structs instead of just names
     pub fn core_segment_structs(&self) -> Vec<&Segment> {
-        let core_names = self.core_segments();
-        let name_set: std::collections::HashSet<_> = core_names.iter().collect();
+        self.segments_named(&self.core_segments())
+    }
+
+    /// Segment structs for the given names, in GFA order
+    pub fn segments_named(&self, names: &[String]) -> Vec<&Segment> {
+        let name_set: HashSet<_> = names.iter().collect();
 
         self.segments
             .iter()
             .filter(|seg| name_set.contains(&seg.name))
             .collect()
     }
+
+    /// Every segment's linked neighbours, each with the end of the segment
+    /// (in its forward orientation) the link attaches to. Segments without
+    /// links are absent.
+    pub fn neighbors(&self) -> HashMap<&str, Vec<(&str, SegmentEnd)>> {
+        let mut neighbors: HashMap<&str, Vec<(&str, SegmentEnd)>> = HashMap::new();
+        for link in &self.links {
+            // `from+` leaves through its end, `to+` is entered at its start
+            let from_end = if link.from_orient == '-' {
+                SegmentEnd::Start
+            } else {
+                SegmentEnd::End
+            };
+            let to_end = if link.to_orient == '-' {
+                SegmentEnd::End
+            } else {
+                SegmentEnd::Start
+            };
+            neighbors
+                .entry(link.from.as_str())
+                .or_default()
+                .push((link.to.as_str(), from_end));
+            neighbors
+                .entry(link.to.as_str())
+                .or_default()
+                .push((link.from.as_str(), to_end));
+        }
+        neighbors
+    }
+
+    /// Variable regions of the graph and the core segment ends flanking
+    /// them. Non-core segments joined by links, or linked to the same end of
+    /// a core segment (the alternative branches of a bubble), form one
+    /// bubble; each core segment end linked into it is an anchor. Bubbles
+    /// with no core anchor are left out. Numbered in GFA order.
+    pub fn bubbles(&self) -> Vec<Bubble> {
+        let core: HashSet<String> = self.core_segments().into_iter().collect();
+        let neighbors = self.neighbors();
+        let is_bubble = |name: &str| !core.contains(name);
+
+        // Union-find over the non-core segments
+        let mut parent: HashMap<&str, &str> = HashMap::new();
+        fn find<'a>(parent: &mut HashMap<&'a str, &'a str>, name: &'a str) -> &'a str {
+            let up = *parent.entry(name).or_insert(name);
+            if up == name {
+                return name;
+            }
+            let root = find(parent, up);
+            parent.insert(name, root);
+            root
+        }
+        fn union<'a>(parent: &mut HashMap<&'a str, &'a str>, a: &'a str, b: &'a str) {
+            let (a, b) = (find(parent, a), find(parent, b));
+            if a != b {
+                parent.insert(b, a);
+            }
+        }
+
+        for (&segment, linked) in &neighbors {
+            if is_bubble(segment) {
+                for &(other, _) in linked.iter().filter(|(other, _)| is_bubble(other)) {
+                    union(&mut parent, segment, other);
+                }
+            } else {
+                for end in [SegmentEnd::Start, SegmentEnd::End] {
+                    let mut branches = linked
+                        .iter()
+                        .filter(|&&(other, e)| e == end && is_bubble(other))
+                        .map(|&(other, _)| other);
+                    if let Some(first) = branches.next() {
+                        for other in branches {
+                            union(&mut parent, first, other);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Anchors per bubble root, then members, in GFA order
+        let mut anchors: HashMap<&str, Vec<(String, SegmentEnd)>> = HashMap::new();
+        let mut members: HashMap<&str, Vec<String>> = HashMap::new();
+        let mut order: Vec<&str> = Vec::new();
+        for segment in &self.segments {
+            let name = segment.name.as_str();
+            let Some(linked) = neighbors.get(name) else {
+                continue;
+            };
+            if is_bubble(name) {
+                let root = find(&mut parent, name);
+                if !members.contains_key(root) {
+                    order.push(root);
+                }
+                members.entry(root).or_default().push(name.to_string());
+                continue;
+            }
+            for end in [SegmentEnd::Start, SegmentEnd::End] {
+                if let Some(&(other, _)) = linked
+                    .iter()
+                    .find(|&&(other, e)| e == end && is_bubble(other))
+                {
+                    let root = find(&mut parent, other);
+                    anchors
+                        .entry(root)
+                        .or_default()
+                        .push((name.to_string(), end));
+                }
+            }
+        }
+
+        order
+            .into_iter()
+            .filter_map(|root| Some((root, anchors.remove(root)?)))
+            .enumerate()
+            .map(|(i, (root, anchors))| Bubble {
+                name: format!("bubble{}", i + 1),
+                segments: members.remove(root).unwrap_or_default(),
+                anchors,
+            })
+            .collect()
+    }
+}
+
+/// One end of a segment, in its forward orientation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SegmentEnd {
+    Start,
+    End,
+}
+
+impl SegmentEnd {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SegmentEnd::Start => "start",
+            SegmentEnd::End => "end",
+        }
+    }
+}
+
+/// A variable region of the graph, from `Gfa::bubbles`.
+#[derive(Debug, Clone)]
+pub struct Bubble {
+    pub name: String,
+    /// Its non-core segments, in GFA order
+    pub segments: Vec<String>,
+    /// Core segment ends linked into it
+    pub anchors: Vec<(String, SegmentEnd)>,
 }
 
 enum GfaLine {
+    Header(Option<GfaVersion>),
     Segment(Segment),
     Link(Link),
     Path(GfaPath),
@@ -66,6 +351,30 @@ pub struct Segment {
     pub sequence: String,
 }
 
+/// One visit of a segment by a path.
+#[derive(Debug, Clone)]
+pub struct PathPlacement {
+    pub path: String,
+    /// 0-based offset of the visit's first base in the path
+    pub start: u64,
+    pub segment_len: u64,
+    pub reverse: bool,
+}
+
+impl PathPlacement {
+    /// 0-based half-open interval and strand, in the path's coordinates, of
+    /// the `len` bases at `offset` within the segment.
+    pub fn interval(&self, offset: usize, len: usize) -> (u64, u64, char) {
+        let (offset, len) = (offset as u64, len as u64);
+        if self.reverse {
+            let start = self.start + self.segment_len - offset - len;
+            (start, start + len, '-')
+        } else {
+            (self.start + offset, self.start + offset + len, '+')
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Link {
     from: String,
@@ -78,14 +387,27 @@ pub struct Link {
 #[derive(Debug)]
 pub struct GfaPath {
     name: String,
+    /// Sample coordinate of the path's first base (W lines), 0 for P lines
+    start: u64,
     segments: Vec<(String, char)>,
     overlaps: Vec<String>,
 }
 
-fn parse_line(line: &str) -> Option<GfaLine> {
+fn parse_line(line: &str, version: GfaVersion) -> Option<GfaLine> {
     let fields: Vec<&str> = line.split('\t').collect();
 
     match fields.get(0)? {
+        &"H" => Some(GfaLine::Header(
+            fields[1..]
+                .iter()
+                .find_map(|tag| tag.strip_prefix("VN:Z:"))
+                .map(GfaVersion::from_tag),
+        )),
+        // GFA2: S <sid> <slen> <sequence>
+        &"S" if version == GfaVersion::Gfa2 => Some(GfaLine::Segment(Segment {
+            name: fields.get(1)?.to_string(),
+            sequence: fields.get(3)?.to_string(),
+        })),
         &"S" => Some(GfaLine::Segment(Segment {
             name: fields.get(1)?.to_string(),
             sequence: fields.get(2)?.to_string(),
@@ -112,21 +434,103 @@ fn parse_line(line: &str) -> Option<GfaLine> {
 
             Some(GfaLine::Path(GfaPath {
                 name,
+                start: 0,
                 segments,
                 overlaps,
             }))
         }
+        // GFA2: O <oid> <ref>[+-] ..., an ordered group standing in for a
+        // path; references to edges or other groups are dropped later
+        &"O" if version == GfaVersion::Gfa2 => {
+            let name = fields.get(1)?.to_string();
+            let segments: Vec<(String, char)> = fields
+                .get(2)?
+                .split_whitespace()
+                .filter_map(|r| {
+                    let orient = r.chars().last().filter(|c| matches!(c, '+' | '-'))?;
+                    Some((r[..r.len() - 1].to_string(), orient))
+                })
+                .collect();
+
+            Some(GfaLine::Path(GfaPath {
+                name,
+                start: 0,
+                segments,
+                overlaps: Vec::new(),
+            }))
+        }
+        &"W" => {
+            // W <sample> <haplotype> <seqid> <start> <end> <walk>, walk like >s1<s2
+            let name = format!("{}#{}#{}", fields.get(1)?, fields.get(2)?, fields.get(3)?);
+            let start = fields.get(4)?.parse().unwrap_or(0);
+            let walk = fields.get(6)?;
+
+            let steps: Vec<(usize, &str)> = walk.match_indices(['>', '<']).collect();
+            let segments: Vec<(String, char)> = steps
+                .iter()
+                .enumerate()
+                .map(|(i, &(pos, orient))| {
+                    let end = steps.get(i + 1).map_or(walk.len(), |&(next, _)| next);
+                    let orient = if orient == ">" { '+' } else { '-' };
+                    (walk[pos + 1..end].to_string(), orient)
+                })
+                .collect();
+
+            Some(GfaLine::Path(GfaPath {
+                name,
+                start,
+                segments,
+                overlaps: Vec::new(),
+            }))
+        }
         _ => Some(GfaLine::Other(line.to_string())),
     }
 }
 
+/// Keep only the segment references of GFA2 ordered groups, which may also
+/// name edges or other groups; groups left with no segments are dropped.
+fn ordered_groups_as_paths(
+    groups: Vec<GfaPath>,
+    segments: &[Segment],
+    source: &str,
+) -> Vec<GfaPath> {
+    let names: HashSet<&str> = segments.iter().map(|s| s.name.as_str()).collect();
+    groups
+        .into_iter()
+        .filter_map(|mut group| {
+            let before = group.segments.len();
+            group
+                .segments
+                .retain(|(seg, _)| names.contains(seg.as_str()));
+            if group.segments.len() < before {
+                debug!(
+                    "O group {} in {}: ignored {} reference(s) that are not segments",
+                    group.name,
+                    source,
+                    before - group.segments.len()
+                );
+            }
+            if group.segments.is_empty() {
+                warn!(
+                    "O group {} in {} references no segments; not used as a path",
+                    group.name, source
+                );
+                return None;
+            }
+            Some(group)
+        })
+        .collect()
+}
+
+/// Parse a GFA file, or standard input when `path` is `-`.
 pub fn parse_gfa_file(path: &str) -> std::io::Result<Gfa> {
-    let file = std::fs::File::open(path)?;
-    let reader = std::io::BufReader::new(file);
+    let reader = crate::io::utils::open_input(path)?;
 
+    let mut version = GfaVersion::Gfa1;
     let mut segments = Vec::new();
     let mut links = Vec::new();
     let mut paths = Vec::new();
+    let mut unordered_groups = 0;
 
     for line in reader.lines() {
         let line = line?;
@@ -134,19 +538,42 @@ pub fn parse_gfa_file(path: &str) -> std::io::Result<Gfa> {
             continue;
         }
 
-        if let Some(parsed) = parse_line(&line) {
+        if let Some(parsed) = parse_line(&line, version) {
             match parsed {
+                GfaLine::Header(Some(v)) => version = v,
+                GfaLine::Header(None) => (),
                 GfaLine::Segment(s) => segments.push(s),
                 GfaLine::Link(l) => links.push(l),
                 GfaLine::Path(p) => paths.push(p),
+                GfaLine::Other(other) if other.starts_with("U\t") => unordered_groups += 1,
                 GfaLine::Other(_) => (),
             }
         }
     }
 
-    Ok(Gfa {
+    if version == GfaVersion::Gfa2 {
+        paths = ordered_groups_as_paths(paths, &segments, path);
+        if unordered_groups > 0 {
+            info!(
+                "Ignoring {} unordered (U) group(s) in {}; only O groups are used as paths",
+                unordered_groups, path
+            );
+        }
+    }
+
+    let gfa = Gfa {
+        version,
         segments,
         links,
         paths,
-    })
+    };
+
+    for name in gfa.duplicate_segment_names() {
+        warn!(
+            "Segment name {} is defined more than once in {}",
+            name, path
+        );
+    }
+
+    Ok(gfa)
 }
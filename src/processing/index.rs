@@ -1,58 +1,474 @@
 use bincode::{DefaultOptions, Options};
+use bio::alphabets::dna::revcomp;
 use cbl::CBL;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use indicatif::{ProgressBar, ProgressStyle};
 use log::*;
 use needletail::parse_fastx_file;
 use rayon::prelude::*;
-use serde::{de::DeserializeOwned, Serialize};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::path::Path;
+use std::io::{BufRead, BufReader};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tempfile::NamedTempFile;
 
-use crate::io::utils;
+use crate::cli::{BuildArgs, FastaCase, OffTargetOptions, PrefixBits};
+use crate::core::probes::kmer_gene_index;
+use crate::core::seq::reverse_complement;
+use crate::io::{output, utils};
+use crate::processing::align;
+use crate::processing::query_cache::QueryCache;
 use crate::GeneKmers;
 
-const K: usize = 49;
-const PREFIX_BITS: usize = 24;
+/// Length of the k-mers stored in an index.
+pub const K: usize = 49;
 type T = u128;
 
-fn write_index<S: Serialize, P: AsRef<Path> + Copy>(index: &S, path: P) {
-    let output = File::create(path)
-        .unwrap_or_else(|_| panic!("Failed to open {}", path.as_ref().to_str().unwrap()));
-    let mut writer = BufWriter::new(output);
-    info!("Writing the index to {}", path.as_ref().to_str().unwrap());
+/// Leading bytes of an index file with a header. Older indexes start
+/// directly with the CBL, with a `VGI1` header lacking the prefix size, or
+/// with a `VGI2` header lacking the strand setting.
+const INDEX_MAGIC: &[u8; 4] = b"VGI3";
+const INDEX_MAGIC_V2: &[u8; 4] = b"VGI2";
+const INDEX_MAGIC_V1: &[u8; 4] = b"VGI1";
+
+/// Prefix size of indexes written before it was configurable.
+const LEGACY_PREFIX_BITS: PrefixBits = PrefixBits::P24;
+
+/// Leading bytes of a gzip stream; compressed indexes keep the `.cbl` name
+/// and are recognised by these instead.
+const GZIP_MAGIC: &[u8; 2] = &[0x1f, 0x8b];
+
+/// Upper bound on gzip's expansion ratio, used to cap decompressed reads.
+const GZIP_MAX_RATIO: u64 = 1032;
+
+/// How FASTA and index files are read and written, for building and
+/// querying alike.
+#[derive(Debug, Clone, Copy)]
+pub struct FileOptions {
+    /// Retries of transient open/create failures (`--io_retries`)
+    pub io_retries: u32,
+    /// Case FASTA sequences are put in before indexing (`--fasta_case`)
+    pub fasta_case: FastaCase,
+}
+
+/// How an index was built, stored ahead of the CBL so queries can adapt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexHeader {
+    pub k: usize,
+    pub canonical: bool,
+    pub prefix_bits: usize,
+    /// Every sequence was inserted along with its reverse complement
+    pub both_strands: bool,
+}
+
+/// Header layout written under `INDEX_MAGIC_V2`.
+#[derive(Deserialize)]
+struct IndexHeaderV2 {
+    k: usize,
+    canonical: bool,
+    prefix_bits: usize,
+}
+
+/// Header layout written under `INDEX_MAGIC_V1`.
+#[derive(Deserialize)]
+struct IndexHeaderV1 {
+    k: usize,
+    canonical: bool,
+}
+
+/// Read the header following `magic` in the current layout, or `None` when
+/// `magic` does not start a header.
+fn read_header<O: Options, R: Read>(
+    magic: &[u8; 4],
+    options: O,
+    reader: R,
+) -> Option<bincode::Result<IndexHeader>> {
+    Some(match magic {
+        INDEX_MAGIC => options.deserialize_from(reader),
+        INDEX_MAGIC_V2 => options
+            .deserialize_from(reader)
+            .map(|v2: IndexHeaderV2| IndexHeader {
+                k: v2.k,
+                canonical: v2.canonical,
+                prefix_bits: v2.prefix_bits,
+                both_strands: false,
+            }),
+        INDEX_MAGIC_V1 => options
+            .deserialize_from(reader)
+            .map(|v1: IndexHeaderV1| IndexHeader {
+                k: v1.k,
+                canonical: v1.canonical,
+                prefix_bits: LEGACY_PREFIX_BITS.bits(),
+                both_strands: false,
+            }),
+        _ => return None,
+    })
+}
+
+/// A CBL for one of the supported `--prefix_bits`. The prefix size is a
+/// const generic, so each value is a separate type.
+enum Index {
+    P20(CBL<K, T, 20>),
+    P24(CBL<K, T, 24>),
+    P28(CBL<K, T, 28>),
+}
+
+/// Run `$body` with `$cbl` bound to whichever CBL `$index` holds.
+macro_rules! each_cbl {
+    ($index:expr, $cbl:ident => $body:expr) => {
+        match $index {
+            Index::P20($cbl) => $body,
+            Index::P24($cbl) => $body,
+            Index::P28($cbl) => $body,
+        }
+    };
+}
+
+impl Index {
+    fn new(prefix_bits: PrefixBits, canonical: bool) -> Self {
+        match (prefix_bits, canonical) {
+            (PrefixBits::P20, true) => Index::P20(CBL::new_canonical()),
+            (PrefixBits::P20, false) => Index::P20(CBL::new()),
+            (PrefixBits::P24, true) => Index::P24(CBL::new_canonical()),
+            (PrefixBits::P24, false) => Index::P24(CBL::new()),
+            (PrefixBits::P28, true) => Index::P28(CBL::new_canonical()),
+            (PrefixBits::P28, false) => Index::P28(CBL::new()),
+        }
+    }
+
+    fn prefix_bits(&self) -> PrefixBits {
+        match self {
+            Index::P20(_) => PrefixBits::P20,
+            Index::P24(_) => PrefixBits::P24,
+            Index::P28(_) => PrefixBits::P28,
+        }
+    }
+
+    fn insert_seq(&mut self, seq: &[u8]) {
+        each_cbl!(self, cbl => {
+            cbl.insert_seq(seq);
+        })
+    }
+
+    fn count(&self) -> usize {
+        each_cbl!(self, cbl => cbl.count())
+    }
+
+    fn contains(&mut self, seq: &[u8]) -> bool {
+        each_cbl!(self, cbl => cbl.contains_seq(seq).iter().any(|&x| x))
+    }
+
+    fn contains_all(&mut self, seq: &[u8]) -> bool {
+        each_cbl!(self, cbl => cbl.contains_seq(seq).iter().all(|&x| x))
+    }
+
+    /// Keep only the k-mers also in `other`, which must share the prefix size.
+    fn intersect(&mut self, other: &mut Index) -> Result<(), Box<dyn std::error::Error>> {
+        match (self, other) {
+            (Index::P20(a), Index::P20(b)) => *a &= b,
+            (Index::P24(a), Index::P24(b)) => *a &= b,
+            (Index::P28(a), Index::P28(b)) => *a &= b,
+            (a, b) => {
+                return Err(format!(
+                    "indexes use different prefix bits ({} and {})",
+                    a.prefix_bits().bits(),
+                    b.prefix_bits().bits()
+                )
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    fn deserialize_from<O: Options, R: Read>(
+        prefix_bits: PrefixBits,
+        options: O,
+        reader: R,
+    ) -> bincode::Result<Self> {
+        Ok(match prefix_bits {
+            PrefixBits::P20 => Index::P20(options.deserialize_from(reader)?),
+            PrefixBits::P24 => Index::P24(options.deserialize_from(reader)?),
+            PrefixBits::P28 => Index::P28(options.deserialize_from(reader)?),
+        })
+    }
+}
+
+impl Serialize for Index {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        each_cbl!(self, cbl => cbl.serialize(serializer))
+    }
+}
+
+/// An index built with a different `--prefix_bits` than the query expects.
+#[derive(Debug)]
+struct PrefixMismatch {
+    path: PathBuf,
+    found: PrefixBits,
+    expected: PrefixBits,
+}
+
+impl std::fmt::Display for PrefixMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} was built with --prefix_bits {} but the query uses {}",
+            self.path.display(),
+            self.found.bits(),
+            self.expected.bits()
+        )
+    }
+}
+
+impl std::error::Error for PrefixMismatch {}
+
+/// Serialize an index and its header atomically: write to a temporary file
+/// (in `tmpdir` if given, otherwise next to the destination) and move it into
+/// place, so an interrupted build never leaves a truncated `.cbl` behind.
+/// With `compress` the whole file is gzipped.
+fn write_index<P: AsRef<Path> + Copy>(
+    index: &Index,
+    header: &IndexHeader,
+    path: P,
+    tmpdir: Option<&Path>,
+    compress: bool,
+    io_retries: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dest = path.as_ref();
+    let dest_dir = match dest.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let temp_dir = tmpdir.unwrap_or(dest_dir);
+    let temp = utils::retry_io(temp_dir, io_retries, || NamedTempFile::new_in(temp_dir))?;
+
+    info!(
+        "Writing the {}index to {}",
+        if compress { "compressed " } else { "" },
+        dest.display()
+    );
+    let mut file = BufWriter::new(temp.as_file());
+    if compress {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        serialize_index(&mut encoder, header, index)?;
+        // Finishing writes the gzip trailer, reporting any error dropping would lose
+        file = encoder.finish()?;
+    } else {
+        serialize_index(&mut file, header, index)?;
+    }
+    file.flush()?;
+    drop(file);
+
+    if let Err(e) = temp.persist(dest) {
+        // Renaming fails across filesystems; copy next to the destination first
+        let staged = utils::retry_io(dest_dir, io_retries, || NamedTempFile::new_in(dest_dir))?;
+        utils::retry_io(dest, io_retries, || {
+            std::fs::copy(e.file.path(), staged.path())
+        })?;
+        staged.persist(dest)?;
+    }
+    Ok(())
+}
+
+/// Write the magic, `header` and `index` to `writer`, as `read_index` expects.
+fn serialize_index<W: Write>(
+    writer: &mut W,
+    header: &IndexHeader,
+    index: &Index,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let options = DefaultOptions::new()
+        .with_varint_encoding()
+        .reject_trailing_bytes();
+    writer.write_all(INDEX_MAGIC)?;
+    options.serialize_into(&mut *writer, header)?;
+    options.serialize_into(&mut *writer, index)?;
+    Ok(())
+}
+
+/// Approximate in-memory size of an index, taken as its serialized size.
+fn estimated_index_bytes<S: Serialize>(index: &S) -> u64 {
     DefaultOptions::new()
         .with_varint_encoding()
-        .reject_trailing_bytes()
-        .serialize_into(&mut writer, &index)
-        .unwrap();
+        .serialized_size(index)
+        .unwrap_or(0)
 }
 
-fn read_index<D: DeserializeOwned, P: AsRef<Path> + Copy>(path: P) -> D {
-    let index = File::open(path)
-        .unwrap_or_else(|_| panic!("Failed to open {}", path.as_ref().to_str().unwrap()));
-    let reader = BufReader::new(index);
+/// Deserialize an index and its header (`None` for indexes written before
+/// headers existed), decompressing gzipped indexes transparently. Reads are
+/// capped at the file's (maximum decompressed) size so a corrupt length field
+/// fails with a size-limit error instead of a huge allocation. With
+/// `expected` set, an index of another prefix size is refused before its
+/// CBL is read.
+fn read_index<P: AsRef<Path> + Copy>(
+    path: P,
+    expected: Option<PrefixBits>,
+    io_retries: u32,
+) -> Result<(Option<IndexHeader>, Index), Box<dyn std::error::Error>> {
+    let index = utils::retry_io(path.as_ref(), io_retries, || File::open(path))
+        .map_err(|e| format!("Failed to open {}: {}", path.as_ref().display(), e))?;
+    let file_size = index.metadata()?.len();
+    let mut reader = BufReader::new(index);
     info!(
         "Reading the index stored in {}",
         path.as_ref().to_str().unwrap()
     );
-    DefaultOptions::new()
+    let corrupt = |e: bincode::Error| format!("Corrupt index {}: {}", path.as_ref().display(), e);
+
+    if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        let options = DefaultOptions::new()
+            .with_varint_encoding()
+            .reject_trailing_bytes()
+            .with_limit(file_size.saturating_mul(GZIP_MAX_RATIO));
+        let mut decoder = BufReader::new(GzDecoder::new(reader));
+        let mut magic = [0u8; 4];
+        decoder.read_exact(&mut magic)?;
+        let header = match read_header(&magic, options, &mut decoder) {
+            Some(header) => header.map_err(corrupt)?,
+            None => {
+                return Err(format!(
+                    "Corrupt index {}: compressed file has no index header",
+                    path.as_ref().display()
+                )
+                .into())
+            }
+        };
+        let prefix_bits = checked_prefix_bits(path.as_ref(), Some(header), expected)?;
+        let decoded =
+            Index::deserialize_from(prefix_bits, options, &mut decoder).map_err(corrupt)?;
+        return Ok((Some(header), decoded));
+    }
+
+    let options = DefaultOptions::new()
         .with_varint_encoding()
         .reject_trailing_bytes()
-        .deserialize_from(reader)
-        .unwrap()
+        .with_limit(file_size);
+    let mut magic = [0u8; 4];
+    let header = match reader.read_exact(&mut magic) {
+        Ok(()) => read_header(&magic, options, &mut reader)
+            .transpose()
+            .map_err(corrupt)?,
+        Err(_) => None,
+    };
+    if header.is_none() {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+    let prefix_bits = checked_prefix_bits(path.as_ref(), header, expected)?;
+    let decoded = Index::deserialize_from(prefix_bits, options, &mut reader).map_err(corrupt)?;
+    Ok((header, decoded))
+}
+
+/// Prefix size recorded in `header` (legacy indexes use the old fixed
+/// size), checked against `expected`. An index of another k is refused, as
+/// its k-mers can never match this build's queries.
+fn checked_prefix_bits(
+    path: &Path,
+    header: Option<IndexHeader>,
+    expected: Option<PrefixBits>,
+) -> Result<PrefixBits, Box<dyn std::error::Error>> {
+    if let Some(header) = header.filter(|header| header.k != K) {
+        return Err(format!(
+            "{} was built with k = {} but this build of visiogen uses k = {}",
+            path.display(),
+            header.k,
+            K
+        )
+        .into());
+    }
+    let found = match header {
+        Some(header) => PrefixBits::from_bits(header.prefix_bits).ok_or_else(|| {
+            format!(
+                "Corrupt index {}: unsupported prefix bits {}",
+                path.display(),
+                header.prefix_bits
+            )
+        })?,
+        None => LEGACY_PREFIX_BITS,
+    };
+    match expected {
+        Some(expected) if expected != found => Err(PrefixMismatch {
+            path: path.to_path_buf(),
+            found,
+            expected,
+        }
+        .into()),
+        _ => Ok(found),
+    }
+}
+
+/// Index every record of a FASTA in memory, with `both_strands` also its
+/// reverse complement. Malformed records are skipped with a warning and
+/// counted, so one bad sequence does not lose the file.
+fn index_fasta(
+    fasta_path: &Path,
+    canonical: bool,
+    both_strands: bool,
+    prefix_bits: PrefixBits,
+    case: FastaCase,
+) -> Result<(Index, usize), Box<dyn std::error::Error>> {
+    let mut cbl = Index::new(prefix_bits, canonical);
+
+    let mut reader = parse_fastx_file(fasta_path)?;
+    let mut skipped = 0;
+    let mut last_error_at = None;
+    while let Some(record) = reader.next() {
+        match record {
+            Ok(seqrec) => {
+                let mut seq = seqrec.seq();
+                if case != FastaCase::Preserve {
+                    case.apply_bytes(seq.to_mut());
+                }
+                cbl.insert_seq(&seq);
+                if both_strands {
+                    cbl.insert_seq(&revcomp(seq.as_ref()));
+                }
+            }
+            Err(e) => {
+                // Some errors (a bad first byte, a failed read) leave the
+                // parser where it was; give up rather than loop on them
+                let at = reader.position().byte();
+                if last_error_at == Some(at) {
+                    return Err(e.into());
+                }
+                last_error_at = Some(at);
+                skipped += 1;
+                warn!("Skipping malformed record in {:?}: {}", fasta_path, e);
+            }
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "Skipped {} malformed record(s) in {:?}",
+            skipped, fasta_path
+        );
+    }
+    Ok((cbl, skipped))
 }
 
 pub fn build_indexes_for_all_fastas(
     fasta_directory: &Path,
     threads: usize,
-    canonical: bool,
     recursive: bool,
+    tmpdir: Option<&Path>,
+    build_args: &BuildArgs,
+    prefix_bits: PrefixBits,
+    files: FileOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    utils::configure_thread_pool(threads);
+    let pool = utils::thread_pool(threads)?;
+    let BuildArgs {
+        canonical,
+        build_batch,
+        compress_index: compress,
+        both_strands,
+    } = *build_args;
+    // Both strands are stored explicitly, so k-mers are kept as they are;
+    // clap refuses an explicit --canonical alongside --both_strands
+    let canonical = canonical && !both_strands;
 
     let fasta_files =
         utils::find_files_with_extensions(fasta_directory, &["fasta", "fa"], recursive)?;
@@ -62,7 +478,23 @@ pub fn build_indexes_for_all_fastas(
         return Ok(());
     }
 
-    info!("Found {} FASTA files to index", total_files);
+    // Every file in a batch holds its whole index in memory until written,
+    // so the batch size bounds peak memory
+    let batch_size = if build_batch == 0 {
+        total_files
+    } else {
+        build_batch
+    };
+    info!(
+        "Found {} FASTA files to index ({} at a time, {} prefix bits)",
+        total_files,
+        batch_size.min(total_files),
+        prefix_bits.bits()
+    );
+    let largest_index = AtomicU64::new(0);
+    let total_kmers = AtomicU64::new(0);
+    let skipped_records = AtomicU64::new(0);
+    let started = Instant::now();
 
     // progress bar
     let progress = ProgressBar::new(total_files as u64);
@@ -71,81 +503,293 @@ pub fn build_indexes_for_all_fastas(
         .unwrap()
         .progress_chars("##-"));
 
-    fasta_files.par_iter().for_each(|fasta_path| {
-        info!("Indexing {:?}", fasta_path);
+    for batch in fasta_files.chunks(batch_size) {
+        pool.install(|| {
+            batch.par_iter().for_each(|fasta_path| {
+                info!("Indexing {:?}", fasta_path);
 
-        let result = (|| {
-            let mut cbl = if canonical {
-                CBL::<K, T>::new_canonical()
-            } else {
-                CBL::<K, T>::new()
-            };
+                let result = (|| {
+                    let insert_started = Instant::now();
+                    let (cbl, skipped) = index_fasta(
+                        fasta_path,
+                        canonical,
+                        both_strands,
+                        prefix_bits,
+                        files.fasta_case,
+                    )?;
+                    skipped_records.fetch_add(skipped as u64, Ordering::Relaxed);
+                    let kmers = cbl.count();
+                    total_kmers.fetch_add(kmers as u64, Ordering::Relaxed);
+                    debug!(
+                        "Inserted {:?} in {:.2}s",
+                        fasta_path,
+                        insert_started.elapsed().as_secs_f64()
+                    );
+                    let index_bytes = estimated_index_bytes(&cbl);
+                    largest_index.fetch_max(index_bytes, Ordering::Relaxed);
+                    info!(
+                        "File {:?} contains {} {}{K}-mers (index ~{:.1} MiB in memory)",
+                        fasta_path,
+                        kmers,
+                        if canonical {
+                            "canonical "
+                        } else if both_strands {
+                            "two-strand "
+                        } else {
+                            ""
+                        },
+                        index_bytes as f64 / (1024.0 * 1024.0)
+                    );
 
-            let mut reader = parse_fastx_file(fasta_path)?;
-            while let Some(record) = reader.next() {
-                let seqrec = record?;
-                cbl.insert_seq(&seqrec.seq());
-            }
+                    // Write index next to original file
+                    let mut index_path = fasta_path.clone();
+                    index_path.set_extension("cbl");
+                    let header = IndexHeader {
+                        k: K,
+                        canonical,
+                        prefix_bits: prefix_bits.bits(),
+                        both_strands,
+                    };
+                    write_index(
+                        &cbl,
+                        &header,
+                        &index_path,
+                        tmpdir,
+                        compress,
+                        files.io_retries,
+                    )?;
 
-            let kmers = cbl.count();
-            info!(
-                "File {:?} contains {} {}{K}-mers",
-                fasta_path,
-                kmers,
-                if canonical { "canonical " } else { "" }
-            );
+                    Ok::<_, Box<dyn std::error::Error>>(())
+                })();
+
+                if let Err(e) = result {
+                    warn!("Error indexing {:?}: {}", fasta_path, e);
+                }
 
-            // Write index next to original file
-            let mut index_path = fasta_path.clone();
-            index_path.set_extension("cbl");
-            write_index(&cbl, &index_path);
+                progress.inc(1);
+            });
+        });
+    }
 
-            Ok::<_, Box<dyn std::error::Error>>(())
-        })();
+    progress.finish_with_message(format!("Indexing complete for all {} files", total_files));
 
-        if let Err(e) = result {
-            warn!("Error indexing {:?}: {}", fasta_path, e);
-        }
+    // Throughput for capacity planning; guard against a zero-length clock
+    let seconds = started.elapsed().as_secs_f64().max(f64::EPSILON);
+    let kmers = total_kmers.load(Ordering::Relaxed);
+    info!(
+        "Indexed {} file(s) with {} k-mers in {:.1}s ({:.2} files/s, {:.0} k-mers/s)",
+        total_files,
+        kmers,
+        seconds,
+        total_files as f64 / seconds,
+        kmers as f64 / seconds
+    );
 
-        progress.inc(1);
-    });
+    let skipped = skipped_records.load(Ordering::Relaxed);
+    if skipped > 0 {
+        warn!(
+            "Skipped {} malformed FASTA record(s) across all files",
+            skipped
+        );
+    }
 
-    progress.finish_with_message(format!("Indexing complete for all {} files", total_files));
+    let largest_mib = largest_index.load(Ordering::Relaxed) as f64 / (1024.0 * 1024.0);
+    info!(
+        "Largest index held in memory: ~{:.1} MiB; peak for {} concurrent file(s) is roughly {:.1} MiB",
+        largest_mib,
+        batch_size.min(total_files),
+        largest_mib * batch_size.min(total_files) as f64
+    );
     Ok(())
 }
 
+/// Build a tiny index, write it to `dir` and read it back, plain and
+/// compressed, checking that the k-mer survives the round trip. Used by the
+/// `doctor` command.
+pub fn round_trip_self_test(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    round_trip(dir, false)?;
+    round_trip(dir, true).map_err(|e| format!("compressed: {}", e).into())
+}
+
+fn round_trip(dir: &Path, compress: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let sequence = b"ACGTTGCATGCAGTCGATCGTAGCTAGCTAGGCTAGCATCGATCGACTGACGTAGCT";
+    let mut cbl = Index::new(LEGACY_PREFIX_BITS, true);
+    cbl.insert_seq(sequence);
+    let expected = cbl.count();
+
+    let header = IndexHeader {
+        k: K,
+        canonical: true,
+        prefix_bits: LEGACY_PREFIX_BITS.bits(),
+        both_strands: false,
+    };
+    let path = NamedTempFile::new_in(dir)?.into_temp_path();
+    write_index(&cbl, &header, &*path, None, compress, 0)?;
+    let (restored_header, mut restored) = read_index(&*path, Some(LEGACY_PREFIX_BITS), 0)?;
+
+    if restored_header != Some(header) {
+        return Err("index header did not survive the round trip".into());
+    }
+    if restored.count() != expected {
+        return Err(format!(
+            "index held {} k-mers after the round trip, expected {}",
+            restored.count(),
+            expected
+        )
+        .into());
+    }
+    if !restored.contains_all(&sequence[..K]) {
+        return Err("round-tripped index lost an inserted k-mer".into());
+    }
+    Ok(())
+}
+
+/// K-mer overlap between two indexes.
+#[derive(Debug, Clone, Copy)]
+pub struct IndexOverlap {
+    pub kmers_a: usize,
+    pub kmers_b: usize,
+    pub shared: usize,
+}
+
+impl IndexOverlap {
+    pub fn jaccard(&self) -> f64 {
+        let union = self.kmers_a + self.kmers_b - self.shared;
+        if union == 0 {
+            0.0
+        } else {
+            self.shared as f64 / union as f64
+        }
+    }
+}
+
+/// Count the k-mers shared by two indexes using CBL's in-place intersection.
+pub fn index_overlap(
+    a: &Path,
+    b: &Path,
+    io_retries: u32,
+) -> Result<IndexOverlap, Box<dyn std::error::Error>> {
+    let (header_a, mut cbl_a) = read_index(a, None, io_retries)?;
+    let (header_b, mut cbl_b) = read_index(b, None, io_retries)?;
+    let canonical = |h: Option<IndexHeader>| h.map(|h| h.canonical);
+    if canonical(header_a) != canonical(header_b) {
+        warn!(
+            "{} and {} were not built with the same --canonical setting; shared k-mers are undercounted",
+            a.display(),
+            b.display()
+        );
+    }
+    let (kmers_a, kmers_b) = (cbl_a.count(), cbl_b.count());
+
+    cbl_a.intersect(&mut cbl_b)?;
+
+    Ok(IndexOverlap {
+        kmers_a,
+        kmers_b,
+        shared: cbl_a.count(),
+    })
+}
+
+/// Load an off-target index, or build one in memory when `path` is a FASTA
+/// given with `--off_target_fasta`. Also returns whether the index must be
+/// queried in both orientations.
+fn load_off_target(
+    path: &Path,
+    from_fasta: bool,
+    prefix_bits: PrefixBits,
+    files: FileOptions,
+) -> Result<(bool, Index), Box<dyn std::error::Error>> {
+    if from_fasta {
+        info!("Indexing {:?} in memory", path);
+        let (cbl, _) = index_fasta(path, true, false, prefix_bits, files.fasta_case)?;
+        return Ok((false, cbl));
+    }
+
+    let (header, cbl) = read_index(path, Some(prefix_bits), files.io_retries)?;
+    let both_orientations = match header {
+        Some(IndexHeader {
+            canonical,
+            both_strands,
+            ..
+        }) => !canonical && !both_strands,
+        None => {
+            debug!("{:?} has no header; querying both orientations", path);
+            true
+        }
+    };
+    Ok((both_orientations, cbl))
+}
+
+/// Query every probe against the off-target indexes (and FASTAs indexed in
+/// memory), recording each probe's hits in `kmer_hits`. Nothing is dropped
+/// here; see `apply_hit_limit`.
 pub fn query_kmers_across_indexes(
-    index_directory: &Path,
+    index_directories: &[PathBuf],
+    off_target_fastas: &[PathBuf],
     mut filtered_kmers: Vec<GeneKmers>,
     threads: usize,
-    max_hits: usize,
     recursive: bool,
+    options: &OffTargetOptions,
+    files: FileOptions,
 ) -> Result<Vec<GeneKmers>, Box<dyn std::error::Error>> {
-    utils::configure_thread_pool(threads);
+    let pool = utils::thread_pool(threads)?;
 
-    let index_files = utils::find_files_with_extensions(index_directory, &["cbl"], recursive)?;
-    let total_indexes = index_files.len();
+    // Prebuilt indexes first, then FASTAs to index on the fly
+    let mut sources: Vec<(PathBuf, bool)> =
+        utils::find_files_in_directories(index_directories, &["cbl"], recursive)?
+            .into_iter()
+            .map(|path| (path, false))
+            .collect();
+    sources.extend(off_target_fastas.iter().map(|path| (path.clone(), true)));
+    let total_indexes = sources.len();
     if total_indexes == 0 {
-        warn!("No CBL index files found in {:?}", index_directory);
+        warn!("No CBL index files found in {:?}", index_directories);
+        if let Some(matrix_path) = &options.hit_matrix {
+            output::write_hit_matrix(matrix_path, &[], &[])?;
+        }
         return Ok(filtered_kmers);
     }
 
-    info!("Found {} index files to search", total_indexes);
+    info!(
+        "Found {} index files to search ({} FASTA(s) indexed in memory)",
+        total_indexes,
+        off_target_fastas.len()
+    );
+
+    // Each unique kmer is queried once and its hits fanned out to every gene
+    // that uses it (e.g. overlapping genes)
+    let kmer_to_fk_index = kmer_gene_index(&filtered_kmers);
+    let mut kmers: Vec<String> = kmer_to_fk_index.keys().cloned().collect();
+    kmers.sort();
 
-    let mut kmer_to_fk_index: HashMap<String, usize> = HashMap::new();
-    let mut kmers: Vec<String> = Vec::new();
+    let total_probes: usize = filtered_kmers.iter().map(|gk| gk.kmers.len()).sum();
+    info!(
+        "Loaded {} kmers from filtered_kmers ({} unique)",
+        total_probes,
+        kmers.len()
+    );
 
-    for (i, gene_kmers) in filtered_kmers.iter().enumerate() {
-        for probe in &gene_kmers.kmers {
-            let kmer = probe.kmer.clone();
-            kmer_to_fk_index.insert(kmer.clone(), i);
-            kmers.push(kmer);
-        }
-    }
+    // Canonical indexes fold both strands; any other index only holds the
+    // strand it was built from, so it is also queried with the reverse
+    // complement to catch opposite-strand matches
+    let revcomps: Vec<String> = kmers.iter().map(|k| reverse_complement(k)).collect();
 
-    info!("Loaded {} kmers from filtered_kmers", kmers.len());
+    let index_names: Vec<String> = sources
+        .iter()
+        .map(|(path, _)| path.to_string_lossy().into_owned())
+        .collect();
+    let cache = match &options.query_cache {
+        Some(path) => Some(QueryCache::open(
+            Path::new(path),
+            &index_names,
+            &kmers,
+            options.prefix_bits.bits(),
+        )?),
+        None => None,
+    };
 
     let results: Arc<Mutex<HashMap<String, Vec<String>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let mismatched: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
     let progress = ProgressBar::new(total_indexes as u64);
     progress.set_style(ProgressStyle::default_bar()
@@ -153,33 +797,83 @@ pub fn query_kmers_across_indexes(
         .unwrap()
         .progress_chars("#>-"));
 
-    index_files.par_iter().for_each(|index_path| {
-        let result = (|| {
-            let mut cbl: CBL<K, T, PREFIX_BITS> = read_index(index_path);
-            for kmer in &kmers {
-                if cbl.contains_seq(kmer.as_bytes()).iter().any(|&x| x) {
-                    let mut res = results.lock().unwrap();
+    pool.install(|| {
+        sources.par_iter().for_each(|(index_path, from_fasta)| {
+            let index_name = index_path.to_string_lossy().into_owned();
+            let add_hits = |hits: &[String]| {
+                let mut res = results.lock().unwrap();
+                for kmer in hits {
                     res.entry(kmer.clone())
                         .or_default()
-                        .push(index_path.to_string_lossy().into_owned());
+                        .push(index_name.clone());
                 }
+            };
+
+            if let Some(hits) = cache.as_ref().and_then(|c| c.hits(&index_name)) {
+                debug!("{:?} already searched; using the query cache", index_path);
+                add_hits(hits);
+                progress.inc(1);
+                return;
             }
-            Ok::<_, Box<dyn std::error::Error>>(())
-        })();
 
-        if let Err(e) = result {
-            warn!("Error querying {:?}: {}", index_path, e);
-        }
+            let result = (|| {
+                let (both_orientations, mut cbl) =
+                    load_off_target(index_path, *from_fasta, options.prefix_bits, files)?;
+                let hits: Vec<String> = kmers
+                    .iter()
+                    .zip(&revcomps)
+                    .filter(|(kmer, revcomp)| {
+                        cbl.contains(kmer.as_bytes())
+                            || (both_orientations && cbl.contains(revcomp.as_bytes()))
+                    })
+                    .map(|(kmer, _)| kmer.clone())
+                    .collect();
+                add_hits(&hits);
+                if let Some(cache) = &cache {
+                    if let Err(e) = cache.record(index_path, hits) {
+                        warn!(
+                            "Failed to update the query cache for {:?}: {}",
+                            index_path, e
+                        );
+                    }
+                }
+                Ok::<_, Box<dyn std::error::Error>>(())
+            })();
+
+            match result {
+                Err(e) if e.is::<PrefixMismatch>() => {
+                    mismatched.lock().unwrap().push(e.to_string())
+                }
+                Err(e) => warn!("Error querying {:?}: {}", index_path, e),
+                Ok(()) => {}
+            }
 
-        progress.inc(1);
+            progress.inc(1);
+        });
     });
 
     progress.finish_with_message("Kmer query complete.");
 
-    let results = results.lock().unwrap();
+    // Querying with the wrong prefix size would silently miss hits
+    let mismatched = mismatched.into_inner().unwrap();
+    if !mismatched.is_empty() {
+        return Err(format!(
+            "Refusing to query {} index(es) with mismatched prefix bits: {}",
+            mismatched.len(),
+            mismatched.join("; ")
+        )
+        .into());
+    }
+
+    let mut results = results.lock().unwrap();
+
+    if let Some(max_identity) = options.max_offtarget_identity {
+        let hits: &mut HashMap<String, Vec<String>> = &mut results;
+        pool.install(|| refine_hits_by_identity(hits, &kmers, &index_names, max_identity));
+    }
 
     for (kmer, files) in results.iter() {
-        if let Some(&fk_index) = kmer_to_fk_index.get(kmer) {
+        for &fk_index in kmer_to_fk_index.get(kmer).into_iter().flatten() {
             let fk = &mut filtered_kmers[fk_index];
             fk.kmer_hits
                 .entry(kmer.clone())
@@ -188,29 +882,59 @@ pub fn query_kmers_across_indexes(
         }
     }
 
-    let filtered = filtered_kmers
-        .into_iter()
-        .filter(|fk| {
-            fk.kmers
-                .iter()
-                .map(|p| &p.kmer)
-                .any(|k| match fk.kmer_hits.get(k) {
-                    Some(files) => files.len() <= max_hits,
-                    None => true,
-                })
-        })
-        .collect::<Vec<_>>();
+    if let Some(matrix_path) = &options.hit_matrix {
+        let columns: Vec<String> = sources
+            .iter()
+            .map(|(path, _)| path.to_string_lossy().into_owned())
+            .collect();
+        output::write_hit_matrix(matrix_path, &columns, &filtered_kmers)?;
+    }
+
+    // 1 for a probe no index contains, halving at one genome hit and so on;
+    // optionally blended into the design score
+    let weight = options.specificity_weight;
+    for fk in &mut filtered_kmers {
+        let hits = &fk.kmer_hits;
+        for probe in fk.kmers.iter_mut() {
+            let genomes = hits
+                .get(&probe.kmer)
+                .map_or(0, |files| distinct_indexes(files));
+            let specificity = 1.0 / (1.0 + genomes as f32);
+            probe.specificity = Some(specificity);
+            if weight > 0.0 {
+                probe.score = (1.0 - weight) * probe.score + weight * specificity;
+            }
+        }
+    }
 
-    for fk in &filtered {
-        for (kmer, files) in fk.kmer_hits.iter().filter(|(_, v)| v.len() <= max_hits) {
+    for fk in &filtered_kmers {
+        for (kmer, files) in fk.kmer_hits.iter() {
             info!(
+                gene = fk.gene.as_str(), kmer = kmer.as_str();
                 "Kmer {} (gene: {}) found in {} index(es):",
                 kmer,
                 fk.gene,
                 files.len()
             );
             for f in files {
-                info!("  - {}", f);
+                info!(gene = fk.gene.as_str(), kmer = kmer.as_str(); "  - {}", f);
+            }
+        }
+    }
+
+    if options.top_off_targets > 0 {
+        for fk in &filtered_kmers {
+            let ranking = fk.off_target_ranking();
+            if ranking.is_empty() {
+                continue;
+            }
+            info!(
+                gene = fk.gene.as_str();
+                "Top off-target indexes for gene {} (by probes hit):",
+                fk.gene
+            );
+            for (file, count) in ranking.iter().take(options.top_off_targets) {
+                info!(gene = fk.gene.as_str(); "  - {}: {} probe(s)", file, count);
             }
         }
     }
@@ -222,9 +946,319 @@ pub fn query_kmers_across_indexes(
         kmers.len()
     );
 
+    Ok(filtered_kmers)
+}
+
+/// Apply one `max_hits` limit to probes annotated by
+/// `query_kmers_across_indexes`. Probes hitting fewer than
+/// `min_offtarget_genomes` distinct indexes are tolerated outright. The rest
+/// are dropped when over the hit limit (or with any hit under clean_only),
+/// then genes left without probes.
+pub fn apply_hit_limit(
+    gene_kmers: Vec<GeneKmers>,
+    options: &OffTargetOptions,
+    max_hits: usize,
+) -> Vec<GeneKmers> {
+    let min_genomes = options.min_offtarget_genomes;
+    let filtered = gene_kmers
+        .into_iter()
+        .map(|mut fk| {
+            let hits = &fk.kmer_hits;
+            fk.kmers.retain(|p| match hits.get(&p.kmer) {
+                Some(files) if distinct_indexes(files) >= min_genomes => {
+                    !options.clean_only && files.len() <= max_hits
+                }
+                _ => true,
+            });
+
+            let clean = fk
+                .kmers
+                .iter()
+                .filter(|p| !hits.contains_key(&p.kmer))
+                .count();
+            info!(
+                gene = fk.gene.as_str();
+                "Gene {} retained {} fully clean probe(s) of {} (max_hits {})",
+                fk.gene,
+                clean,
+                fk.kmers.len(),
+                max_hits
+            );
+            fk
+        })
+        .filter(|fk| !fk.kmers.is_empty())
+        .collect::<Vec<_>>();
+
     if filtered.is_empty() {
-        warn!("All kmers were filtered out - no kmers matched the criteria");
+        warn!(
+            "All kmers were filtered out at max_hits {} - no kmers matched the criteria",
+            max_hits
+        );
+    }
+    filtered
+}
+
+fn distinct_indexes(files: &[String]) -> usize {
+    files.iter().collect::<HashSet<_>>().len()
+}
+
+/// Re-screen every probe by aligning it, with short exact seeds, against the
+/// FASTA each index was built from. A probe counts as a hit for that index
+/// when its best placement is above `max_identity` percent, so mismatched
+/// off-targets the exact k-mer query misses are caught and weaker k-mer hits
+/// are dropped. Indexes without a source FASTA keep their k-mer hits.
+fn refine_hits_by_identity(
+    results: &mut HashMap<String, Vec<String>>,
+    kmers: &[String],
+    indexes: &[String],
+    max_identity: f64,
+) {
+    info!(
+        "Aligning {} probe(s) against the source FASTAs of {} index(es) (max identity {}%)",
+        kmers.len(),
+        indexes.len(),
+        max_identity
+    );
+
+    let refined: Vec<(String, Vec<String>)> = indexes
+        .par_iter()
+        .filter_map(|index_file| {
+            let Some(fasta) = align::source_fasta_for_index(Path::new(index_file)) else {
+                warn!(
+                    "No source FASTA found for index {}; keeping its k-mer hits",
+                    index_file
+                );
+                return None;
+            };
+            match align::best_identities_in_fasta(&fasta, kmers, align::SEED_LEN) {
+                Ok(identities) => {
+                    let hits = identities
+                        .into_iter()
+                        .filter(|(_, identity)| *identity > max_identity)
+                        .map(|(kmer, _)| kmer)
+                        .collect();
+                    Some((index_file.clone(), hits))
+                }
+                Err(e) => {
+                    warn!(
+                        "Error aligning against {:?}: {}; keeping its k-mer hits",
+                        fasta, e
+                    );
+                    None
+                }
+            }
+        })
+        .collect();
+
+    let (mut dropped, mut added) = (0, 0);
+    for (index_file, hits) in &refined {
+        let hits: HashSet<&String> = hits.iter().collect();
+        for (kmer, files) in results.iter_mut() {
+            if !hits.contains(kmer) {
+                let before = files.len();
+                files.retain(|f| f != index_file);
+                dropped += before - files.len();
+            }
+        }
+        for kmer in hits {
+            let files = results.entry(kmer.clone()).or_default();
+            if !files.contains(index_file) {
+                files.push(index_file.clone());
+                added += 1;
+            }
+        }
+    }
+    results.retain(|_, files| !files.is_empty());
+
+    info!(
+        "Alignment screen: {} hit(s) added above {}% identity, {} k-mer hit(s) dropped at or below it",
+        added, max_identity, dropped
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQUENCE: &[u8] = b"ACGTTGCATGCAGTCGATCGTAGCTAGCTAGGCTAGCATCGATCGACTGACGTAGCTTAGC";
+
+    fn test_header(canonical: bool) -> IndexHeader {
+        IndexHeader {
+            k: K,
+            canonical,
+            prefix_bits: LEGACY_PREFIX_BITS.bits(),
+            both_strands: false,
+        }
+    }
+
+    fn write_test_index(dir: &Path, name: &str, header: &IndexHeader, compress: bool) -> PathBuf {
+        let mut cbl = Index::new(LEGACY_PREFIX_BITS, header.canonical);
+        cbl.insert_seq(SEQUENCE);
+        let path = dir.join(name);
+        write_index(&cbl, header, &path, None, compress, 0).unwrap();
+        path
+    }
+
+    fn error_of<T>(result: Result<T, Box<dyn std::error::Error>>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
     }
 
-    Ok(filtered)
+    #[test]
+    fn truncated_index_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        for compress in [false, true] {
+            let path = write_test_index(dir.path(), "a.cbl", &test_header(true), compress);
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+            assert!(read_index(&path, None, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn garbage_length_fails_at_the_size_limit() {
+        // A valid header followed by a collection claiming u64::MAX entries
+        let mut bytes = INDEX_MAGIC.to_vec();
+        DefaultOptions::new()
+            .with_varint_encoding()
+            .serialize_into(&mut bytes, &test_header(true))
+            .unwrap();
+        bytes.push(0xfd);
+        bytes.extend([0xff; 8]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("garbage.cbl");
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(error_of(read_index(&path, None, 0)).starts_with("Corrupt index"));
+    }
+
+    /// Off-target hits of `probe` across the indexes in `dir`.
+    fn hits_of(dir: &Path, probe: &str) -> Vec<String> {
+        let mut hits = query_one(dir, probe, &[])
+            .kmer_hits
+            .remove(probe)
+            .unwrap_or_default();
+        hits.sort();
+        hits
+    }
+
+    /// `probe`, as the only probe of a gene, queried against the indexes in
+    /// `dir` with the off-target flags `args`.
+    fn query_one(dir: &Path, probe: &str, args: &[&str]) -> GeneKmers {
+        use clap::Parser;
+        let gk = GeneKmers {
+            gene: "g1".to_string(),
+            seqid: "g1".to_string(),
+            start: 1,
+            end: probe.len() as u64,
+            kmers: vec![crate::core::probes::Probes::new(probe.to_string(), vec![0])],
+            strand: "+".to_string(),
+            kmer_hits: HashMap::new(),
+        };
+        let options =
+            OffTargetOptions::parse_from(std::iter::once("visiogen").chain(args.iter().copied()));
+        let files = FileOptions {
+            io_retries: 0,
+            fasta_case: FastaCase::Upper,
+        };
+        let mut queried = query_kmers_across_indexes(
+            &[dir.to_path_buf()],
+            &[],
+            vec![gk],
+            1,
+            false,
+            &options,
+            files,
+        )
+        .unwrap();
+        queried.remove(0)
+    }
+
+    fn reverse_probe() -> String {
+        reverse_complement(std::str::from_utf8(&SEQUENCE[..55]).unwrap())
+    }
+
+    #[test]
+    fn non_canonical_index_matches_the_reverse_strand() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_index(dir.path(), "fwd.cbl", &test_header(false), false);
+        let forward = std::str::from_utf8(&SEQUENCE[..55]).unwrap();
+
+        assert_eq!(hits_of(dir.path(), forward), [path.to_string_lossy()]);
+        assert_eq!(
+            hits_of(dir.path(), &reverse_probe()),
+            [path.to_string_lossy()]
+        );
+    }
+
+    #[test]
+    fn index_of_another_k_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = IndexHeader {
+            k: 31,
+            ..test_header(true)
+        };
+        for compress in [false, true] {
+            let path = write_test_index(dir.path(), "k31.cbl", &header, compress);
+            let error = error_of(read_index(&path, None, 0));
+            assert!(error.contains("k = 31"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn canonical_and_non_canonical_indexes_are_queried_alike() {
+        let dir = tempfile::tempdir().unwrap();
+        let canonical = write_test_index(dir.path(), "canonical.cbl", &test_header(true), false);
+        let stranded = write_test_index(dir.path(), "stranded.cbl", &test_header(false), false);
+        let both = [canonical.to_string_lossy(), stranded.to_string_lossy()];
+        let forward = std::str::from_utf8(&SEQUENCE[..55]).unwrap();
+
+        assert_eq!(hits_of(dir.path(), forward), both);
+        assert_eq!(hits_of(dir.path(), &reverse_probe()), both);
+    }
+
+    #[test]
+    fn written_indexes_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        for compress in [false, true] {
+            if let Err(e) = round_trip(dir.path(), compress) {
+                panic!("compress = {}: {}", compress, e);
+            }
+        }
+    }
+
+    #[test]
+    fn hit_matrix_has_a_column_per_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = write_test_index(dir.path(), "a.cbl", &test_header(true), false);
+        let matrix = dir.path().join("matrix.tsv");
+        let probe = std::str::from_utf8(&SEQUENCE[..55]).unwrap();
+        query_one(
+            dir.path(),
+            probe,
+            &["--hit_matrix", matrix.to_str().unwrap()],
+        );
+
+        let text = std::fs::read_to_string(&matrix).unwrap();
+        assert_eq!(
+            text,
+            format!("gene\tkmer\t{}\ng1\t{}\t1\n", index.display(), probe)
+        );
+    }
+
+    #[test]
+    fn hit_matrix_is_header_only_without_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let matrix = dir.path().join("matrix.tsv");
+        let probe = std::str::from_utf8(&SEQUENCE[..55]).unwrap();
+        query_one(
+            dir.path(),
+            probe,
+            &["--hit_matrix", matrix.to_str().unwrap()],
+        );
+
+        assert_eq!(std::fs::read_to_string(&matrix).unwrap(), "gene\tkmer\n");
+    }
 }
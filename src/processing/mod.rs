@@ -1,3 +1,10 @@
+#[cfg(feature = "index")]
+pub mod align;
+pub mod bed;
 pub mod gff;
 pub mod graph;
+#[cfg(feature = "index")]
 pub mod index;
+pub mod pan;
+#[cfg(feature = "index")]
+pub mod query_cache;
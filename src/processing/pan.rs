@@ -0,0 +1,121 @@
+use log::*;
+use needletail::parse_fastx_file;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use crate::cli::FastaCase;
+use crate::core::probes::GeneKmers;
+use crate::core::seq::reverse_complement;
+use crate::error::{Result, VisiogenError};
+
+/// Pan-probe screen (`--pan_targets`), the inverse of the off-target one:
+/// set each probe's `conservation` to the fraction of targets holding it on
+/// either strand, counting its own target plus every FASTA in `pan_fastas`,
+/// and keep the probes found in at least `min_fraction` of them.
+pub fn keep_conserved(
+    gene_kmers: Vec<GeneKmers>,
+    pan_fastas: &[String],
+    min_fraction: f64,
+    case: FastaCase,
+) -> Result<Vec<GeneKmers>> {
+    let candidates: HashSet<&str> = gene_kmers
+        .iter()
+        .flat_map(|gk| gk.kmers.iter().map(|probe| probe.kmer.as_str()))
+        .collect();
+    let found = pan_fastas
+        .par_iter()
+        .map(|path| kmers_in_fasta(path, &candidates, case))
+        .collect::<Result<Vec<_>>>()?;
+
+    let targets = pan_fastas.len() + 1;
+    let (mut before, mut after) = (0, 0);
+    let gene_kmers = gene_kmers
+        .into_iter()
+        .map(|gk| {
+            let mut kept = gk.kmers.clone();
+            kept.retain_mut(|probe| {
+                let present = 1 + found.iter().filter(|set| set.contains(&probe.kmer)).count();
+                let fraction = present as f64 / targets as f64;
+                probe.conservation = Some(fraction as f32);
+                fraction >= min_fraction
+            });
+            if kept.len() < gk.kmers.len() {
+                info!(
+                    gene = gk.gene.as_str();
+                    "Gene {}: dropped {} probe(s) found in under {:.0}% of {} targets",
+                    gk.gene,
+                    gk.kmers.len() - kept.len(),
+                    min_fraction * 100.0,
+                    targets
+                );
+            }
+            before += gk.kmers.len();
+            after += kept.len();
+            gk.with_kmers(kept)
+        })
+        .collect();
+
+    info!(
+        "Pan-target screen against {} FASTA(s): kept {} of {} probe(s)",
+        pan_fastas.len(),
+        after,
+        before
+    );
+    Ok(gene_kmers)
+}
+
+/// The `candidates` found, on either strand, in any record of the FASTA at
+/// `path` once put in `case`.
+fn kmers_in_fasta(
+    path: &str,
+    candidates: &HashSet<&str>,
+    case: FastaCase,
+) -> Result<HashSet<String>> {
+    let reverse: Vec<(String, &str)> = candidates
+        .iter()
+        .map(|&kmer| (reverse_complement(kmer), kmer))
+        .collect();
+    let mut lookup: HashMap<&[u8], &str> = candidates
+        .iter()
+        .map(|&kmer| (kmer.as_bytes(), kmer))
+        .collect();
+    for (revcomp, kmer) in &reverse {
+        lookup.entry(revcomp.as_bytes()).or_insert(kmer);
+    }
+    let lengths: HashSet<usize> = candidates.iter().map(|kmer| kmer.len()).collect();
+
+    let mut reader = parse_fastx_file(path).map_err(|e| {
+        VisiogenError::FastaParseError(format!("Failed to open --pan_targets {}: {}", path, e))
+    })?;
+    let mut found = HashSet::new();
+    let mut records = 0;
+    while let Some(record) = reader.next() {
+        let seqrec = record.map_err(|e| {
+            VisiogenError::FastaParseError(format!("Malformed record in {}: {}", path, e))
+        })?;
+        let mut seq = seqrec.seq();
+        if case != FastaCase::Preserve {
+            case.apply_bytes(seq.to_mut());
+        }
+        for &len in &lengths {
+            for window in seq.windows(len) {
+                match lookup.get(window) {
+                    Some(&kmer) if !found.contains(kmer) => {
+                        found.insert(kmer.to_string());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        records += 1;
+    }
+
+    info!(
+        "Found {} of {} candidate probe(s) in {} ({} record(s))",
+        found.len(),
+        candidates.len(),
+        path,
+        records
+    );
+    Ok(found)
+}
@@ -0,0 +1,176 @@
+use bincode::{DefaultOptions, Options};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// Leading bytes of a `--query_cache` file.
+const CACHE_MAGIC: &[u8; 4] = b"VGQ1";
+
+/// The hits of one finished index query, as kept in the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedQuery {
+    index: String,
+    /// Size and modification time (ns since the epoch) of the index file
+    fingerprint: (u64, u128),
+    prefix_bits: usize,
+    /// Digest of the sorted probe set the index was queried with
+    probes: u64,
+    hits: Vec<String>,
+}
+
+/// On-disk record of the indexes a screen has finished querying, for
+/// `--query_cache`. Each index is appended as soon as it is done, so an
+/// interrupted screen resumes with only the indexes it had not reached.
+/// Entries are reused only for an unchanged index file queried with the same
+/// probe set and prefix bits; any other entry is dropped when the cache is
+/// opened.
+pub struct QueryCache {
+    done: HashMap<String, CachedQuery>,
+    writer: Mutex<BufWriter<File>>,
+    probes: u64,
+    prefix_bits: usize,
+}
+
+impl QueryCache {
+    /// Open (or create) the cache at `path` for querying the sorted `kmers`,
+    /// keeping only the entries still valid for `indexes`.
+    pub fn open(
+        path: &Path,
+        indexes: &[String],
+        kmers: &[String],
+        prefix_bits: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let probes = probe_digest(kmers);
+        let mut done = HashMap::new();
+        let mut stale = 0;
+        for entry in read_entries(path)? {
+            let current = entry.probes == probes
+                && entry.prefix_bits == prefix_bits
+                && indexes.contains(&entry.index)
+                && fingerprint(Path::new(&entry.index)).ok() == Some(entry.fingerprint);
+            if current {
+                done.insert(entry.index.clone(), entry);
+            } else {
+                stale += 1;
+            }
+        }
+        if stale > 0 {
+            info!(
+                "Dropped {} stale entr{} from the query cache {}",
+                stale,
+                if stale == 1 { "y" } else { "ies" },
+                path.display()
+            );
+        }
+        info!(
+            "Query cache {}: {} of {} index(es) already searched",
+            path.display(),
+            done.len(),
+            indexes.len()
+        );
+
+        // Rewrite with the valid entries only, then append as indexes finish
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(CACHE_MAGIC)?;
+        for entry in done.values() {
+            options().serialize_into(&mut writer, entry)?;
+        }
+        writer.flush()?;
+
+        Ok(QueryCache {
+            done,
+            writer: Mutex::new(writer),
+            probes,
+            prefix_bits,
+        })
+    }
+
+    /// Probes found in `index` by an earlier run, when it was searched.
+    pub fn hits(&self, index: &str) -> Option<&[String]> {
+        self.done.get(index).map(|entry| entry.hits.as_slice())
+    }
+
+    /// Record that `index` was searched and which probes it contains.
+    pub fn record(
+        &self,
+        index: &Path,
+        hits: Vec<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = CachedQuery {
+            index: index.to_string_lossy().into_owned(),
+            fingerprint: fingerprint(index)?,
+            prefix_bits: self.prefix_bits,
+            probes: self.probes,
+            hits,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        options().serialize_into(&mut *writer, &entry)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn options() -> impl Options {
+    DefaultOptions::new().with_varint_encoding()
+}
+
+/// Every entry of an existing cache. A missing file is an empty cache, and a
+/// record cut short by an interruption ends the read.
+fn read_entries(path: &Path) -> Result<Vec<CachedQuery>, Box<dyn std::error::Error>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to open {}: {}", path.display(), e).into()),
+    };
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf()?.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut magic = [0u8; 4];
+    if std::io::Read::read_exact(&mut reader, &mut magic).is_err() || &magic != CACHE_MAGIC {
+        return Err(format!("{} is not a visiogen query cache", path.display()).into());
+    }
+
+    let mut entries = Vec::new();
+    while !reader.fill_buf()?.is_empty() {
+        match options().deserialize_from(&mut reader) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                warn!(
+                    "Query cache {} ends in an incomplete entry ({}); ignoring it",
+                    path.display(),
+                    e
+                );
+                break;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Size and modification time of `path`, to notice rebuilt indexes.
+fn fingerprint(path: &Path) -> std::io::Result<(u64, u128)> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    Ok((metadata.len(), modified))
+}
+
+/// FNV-1a digest of the probe set, stable across builds and platforms.
+fn probe_digest(kmers: &[String]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for kmer in kmers {
+        for byte in kmer.bytes().chain(std::iter::once(b'\n')) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}